@@ -0,0 +1,391 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+pub use turtle::{Color, Point};
+use turtle::{Drawing, Turtle};
+
+use log::warn;
+
+// Everything the VM asks from a single turtle
+pub trait Pen {
+    fn home(&mut self);
+    fn forward(&mut self, distance: f64);
+    fn backward(&mut self, distance: f64);
+    fn left(&mut self, angle: f64);
+    fn right(&mut self, angle: f64);
+    fn pen_up(&mut self);
+    fn pen_down(&mut self);
+    fn is_pen_down(&self) -> bool;
+    fn reset(&mut self);
+    fn set_pen_size(&mut self, size: f64);
+    fn go_to(&mut self, point: Point);
+    fn position(&self) -> Point;
+    fn heading(&self) -> f64;
+    fn set_heading(&mut self, angle: f64);
+    // 0 is instant, 1 to 25 goes from slowest to fastest
+    fn set_speed(&mut self, speed: i32);
+    fn set_pen_color(&mut self, color: Color);
+    fn pen_color(&self) -> Color;
+    fn set_fill_color(&mut self, color: Color);
+    fn fill_color(&self) -> Color;
+    fn begin_fill(&mut self);
+    fn end_fill(&mut self);
+    // Writes text at the position of the turtle, it doesn't move it
+    fn write_text(&mut self, text: &str);
+}
+
+// Where the turtles draw, a window or a headless recorder
+pub trait Canvas {
+    fn set_title(&mut self, title: &str);
+    fn set_background_color(&mut self, color: Color);
+    fn set_size(&mut self, width: u32, height: u32);
+    // Point of the drawing shown in the middle of the canvas
+    fn set_center(&mut self, center: Point);
+    // Returns the id of the new turtle
+    fn add_turtle(&mut self) -> usize;
+    fn turtle_count(&self) -> usize;
+    fn turtle(&mut self, id: usize) -> &mut dyn Pen;
+    // Snapshot of the drawing, called by Frame()
+    fn frame(&mut self) -> Result<(), String>;
+    // Called once the program ends
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for dyn Canvas {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Canvas with {} turtles", self.turtle_count())
+    }
+}
+
+impl Pen for Turtle {
+    fn home(&mut self) { Turtle::home(self) }
+    fn forward(&mut self, distance: f64) { Turtle::forward(self, distance) }
+    fn backward(&mut self, distance: f64) { Turtle::backward(self, distance) }
+    fn left(&mut self, angle: f64) { Turtle::left(self, angle) }
+    fn right(&mut self, angle: f64) { Turtle::right(self, angle) }
+    fn pen_up(&mut self) { Turtle::pen_up(self) }
+    fn pen_down(&mut self) { Turtle::pen_down(self) }
+    fn is_pen_down(&self) -> bool { Turtle::is_pen_down(self) }
+    fn reset(&mut self) { Turtle::reset(self) }
+    fn set_pen_size(&mut self, size: f64) { Turtle::set_pen_size(self, size) }
+    fn go_to(&mut self, point: Point) { Turtle::go_to(self, point) }
+    fn position(&self) -> Point { Turtle::position(self) }
+    fn heading(&self) -> f64 { Turtle::heading(self) }
+    fn set_heading(&mut self, angle: f64) { Turtle::set_heading(self, angle) }
+    fn set_speed(&mut self, speed: i32) {
+        if speed == 0 {
+            Turtle::set_speed(self, "instant")
+        } else {
+            Turtle::set_speed(self, speed)
+        }
+    }
+    fn set_pen_color(&mut self, color: Color) { Turtle::set_pen_color(self, color) }
+    fn pen_color(&self) -> Color { Turtle::pen_color(self) }
+    fn set_fill_color(&mut self, color: Color) { Turtle::set_fill_color(self, color) }
+    fn fill_color(&self) -> Color { Turtle::fill_color(self) }
+    fn begin_fill(&mut self) { Turtle::begin_fill(self) }
+    fn end_fill(&mut self) { Turtle::end_fill(self) }
+    // The turtle crate can't draw text
+    fn write_text(&mut self, text: &str) {
+        let position = Turtle::position(self);
+        warn!("Text can't be drawn on the canvas, \"{}\" was at ({:.1}, {:.1})", text, position.x, position.y);
+    }
+}
+
+// Draws on a window with the turtle crate
+pub struct TurtleCanvas {
+    drawing: Drawing,
+    turtles: Vec<Turtle>
+}
+
+impl TurtleCanvas {
+    pub fn new() -> Self {
+        let mut drawing = Drawing::new();
+        // Turtle 0 is the default one
+        let turtles = vec![drawing.add_turtle()];
+        TurtleCanvas { drawing, turtles }
+    }
+}
+
+impl Canvas for TurtleCanvas {
+    fn set_title(&mut self, title: &str) {
+        self.drawing.set_title(title);
+    }
+
+    fn set_background_color(&mut self, color: Color) {
+        self.drawing.set_background_color(color);
+    }
+
+    fn set_size(&mut self, width: u32, height: u32) {
+        self.drawing.set_size([width, height]);
+    }
+
+    fn set_center(&mut self, center: Point) {
+        self.drawing.set_center(center);
+    }
+
+    fn add_turtle(&mut self) -> usize {
+        self.turtles.push(self.drawing.add_turtle());
+        self.turtles.len() - 1
+    }
+
+    fn turtle_count(&self) -> usize {
+        self.turtles.len()
+    }
+
+    fn turtle(&mut self, id: usize) -> &mut dyn Pen {
+        &mut self.turtles[id]
+    }
+
+    // The window already shows every step
+    fn frame(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn svg_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn svg_color(color: &Color) -> String {
+    format!("rgba({:.0},{:.0},{:.0},{})", color.red, color.green, color.blue, color.alpha)
+}
+
+// Turtle without a window, it keeps its lines as svg elements
+struct SvgTurtle {
+    position: Point,
+    heading: f64,
+    pen_down: bool,
+    pen_size: f64,
+    pen_color: Color,
+    fill_color: Color,
+    // Index in shapes where the fill goes and the points of the polygon
+    fill: Option<(usize, Vec<Point>)>,
+    shapes: Vec<String>
+}
+
+impl SvgTurtle {
+    fn new() -> Self {
+        SvgTurtle {
+            position: Point { x: 0.0, y: 0.0 },
+            heading: 90.0,
+            pen_down: true,
+            pen_size: 1.0,
+            pen_color: Color::rgb(0.0, 0.0, 0.0),
+            fill_color: Color::rgb(0.0, 0.0, 0.0),
+            fill: None,
+            shapes: Vec::new()
+        }
+    }
+
+    // Shapes use turtle coordinates, the canvas flips them when saving
+    fn move_to(&mut self, point: Point) {
+        if self.pen_down {
+            self.shapes.push(format!(
+                "<line x1=\"{:.2}\" y1=\"{:.2}\" x2=\"{:.2}\" y2=\"{:.2}\" stroke=\"{}\" stroke-width=\"{}\" stroke-linecap=\"round\"/>",
+                self.position.x, -self.position.y, point.x, -point.y, svg_color(&self.pen_color), self.pen_size
+            ));
+        }
+        if let Some((_, points)) = &mut self.fill {
+            points.push(point);
+        }
+        self.position = point;
+    }
+}
+
+impl Pen for SvgTurtle {
+    fn home(&mut self) {
+        self.position = Point { x: 0.0, y: 0.0 };
+        self.heading = 90.0;
+    }
+
+    fn forward(&mut self, distance: f64) {
+        let radians = self.heading.to_radians();
+        let point = Point { x: self.position.x + distance * radians.cos(), y: self.position.y + distance * radians.sin() };
+        self.move_to(point);
+    }
+
+    fn backward(&mut self, distance: f64) {
+        self.forward(-distance);
+    }
+
+    fn left(&mut self, angle: f64) {
+        self.heading = (self.heading + angle).rem_euclid(360.0);
+    }
+
+    fn right(&mut self, angle: f64) {
+        self.heading = (self.heading - angle).rem_euclid(360.0);
+    }
+
+    fn pen_up(&mut self) {
+        self.pen_down = false;
+    }
+
+    fn pen_down(&mut self) {
+        self.pen_down = true;
+    }
+
+    fn is_pen_down(&self) -> bool {
+        self.pen_down
+    }
+
+    fn reset(&mut self) {
+        *self = SvgTurtle::new();
+    }
+
+    fn set_pen_size(&mut self, size: f64) {
+        self.pen_size = size;
+    }
+
+    fn go_to(&mut self, point: Point) {
+        self.move_to(point);
+    }
+
+    fn position(&self) -> Point {
+        self.position
+    }
+
+    fn heading(&self) -> f64 {
+        self.heading
+    }
+
+    fn set_heading(&mut self, angle: f64) {
+        self.heading = angle.rem_euclid(360.0);
+    }
+
+    // Frames are taken with Frame(), the speed doesn't change them
+    fn set_speed(&mut self, _speed: i32) {}
+
+    fn set_pen_color(&mut self, color: Color) {
+        self.pen_color = color;
+    }
+
+    fn pen_color(&self) -> Color {
+        self.pen_color
+    }
+
+    fn set_fill_color(&mut self, color: Color) {
+        self.fill_color = color;
+    }
+
+    fn fill_color(&self) -> Color {
+        self.fill_color
+    }
+
+    fn begin_fill(&mut self) {
+        self.fill = Some((self.shapes.len(), vec![self.position]));
+    }
+
+    fn end_fill(&mut self) {
+        if let Some((index, points)) = self.fill.take() {
+            let points: Vec<String> = points.iter().map(|point| format!("{:.2},{:.2}", point.x, -point.y)).collect();
+            // The fill goes under the lines drawn while filling
+            self.shapes.insert(index, format!("<polygon points=\"{}\" fill=\"{}\"/>", points.join(" "), svg_color(&self.fill_color)));
+        }
+    }
+
+    fn write_text(&mut self, text: &str) {
+        self.shapes.push(format!(
+            "<text x=\"{:.2}\" y=\"{:.2}\" fill=\"{}\" font-family=\"sans-serif\">{}</text>",
+            self.position.x, -self.position.y, svg_color(&self.pen_color), svg_escape(text)
+        ));
+    }
+}
+
+// Headless canvas, every Frame() is saved as a numbered svg image
+pub struct SvgCanvas {
+    out_dir: PathBuf,
+    title: String,
+    width: u32,
+    height: u32,
+    center: Point,
+    background: Color,
+    turtles: Vec<SvgTurtle>,
+    frame_count: usize
+}
+
+impl SvgCanvas {
+    pub fn new(out_dir: &str) -> Self {
+        SvgCanvas {
+            out_dir: PathBuf::from(out_dir),
+            title: String::new(),
+            width: 800,
+            height: 600,
+            center: Point { x: 0.0, y: 0.0 },
+            background: Color::rgb(255.0, 255.0, 255.0),
+            turtles: vec![SvgTurtle::new()],
+            frame_count: 0
+        }
+    }
+
+    fn save(&self, file_name: &str) -> Result<(), String> {
+        fs::create_dir_all(&self.out_dir).map_err(|err| format!("Cannot create {}: {}", self.out_dir.display(), err))?;
+
+        // Top left corner of the view, the y axis is flipped in the shapes
+        let left = self.center.x - self.width as f64 / 2.0;
+        let top = -self.center.y - self.height as f64 / 2.0;
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"{x} {y} {w} {h}\">\n<title>{t}</title>\n",
+            w = self.width, h = self.height, x = left, y = top,
+            t = svg_escape(&self.title)
+        );
+        svg.push_str(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"100%\" height=\"100%\" fill=\"{}\"/>\n",
+            left, top, svg_color(&self.background)
+        ));
+        for turtle in self.turtles.iter() {
+            for shape in turtle.shapes.iter() {
+                svg.push_str(shape);
+                svg.push('\n');
+            }
+        }
+        svg.push_str("</svg>\n");
+
+        let path = self.out_dir.join(file_name);
+        fs::write(&path, svg).map_err(|err| format!("Cannot write {}: {}", path.display(), err))
+    }
+}
+
+impl Canvas for SvgCanvas {
+    fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+    }
+
+    fn set_background_color(&mut self, color: Color) {
+        self.background = color;
+    }
+
+    fn set_size(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    fn set_center(&mut self, center: Point) {
+        self.center = center;
+    }
+
+    fn add_turtle(&mut self) -> usize {
+        self.turtles.push(SvgTurtle::new());
+        self.turtles.len() - 1
+    }
+
+    fn turtle_count(&self) -> usize {
+        self.turtles.len()
+    }
+
+    fn turtle(&mut self, id: usize) -> &mut dyn Pen {
+        &mut self.turtles[id]
+    }
+
+    fn frame(&mut self) -> Result<(), String> {
+        self.frame_count += 1;
+        self.save(&format!("frame_{:04}.svg", self.frame_count))
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.save("final.svg")
+    }
+}
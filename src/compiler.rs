@@ -1,14 +1,16 @@
 use pest::Parser;
-use std::fs;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::fmt;
-use std::fs::File;
 use std::io::prelude::*;
 
 use log::{debug, error, log_enabled, info, Level, warn};
 
-use crate::memory::BaseDirs;
+use crate::memory::{BaseDirs, IntWidth};
+use crate::format::{escape_char, escape_string, parse_format, unescape_char, unescape_string, FormatPiece};
+
+// Minimum number of int cases before a switch is lowered to a jump table
+const JUMP_TABLE_MIN_CASES: usize = 4;
 
 #[derive(Parser)]
 #[grammar = "memyself.pest"]
@@ -20,6 +22,7 @@ enum VarType {
   Float,
   Char,
   Bool,
+  String,
   Void
 }
 
@@ -35,17 +38,36 @@ struct Var {
 
 type VarHash = HashMap<String, Var>;
 
+// Type of a struct field, a nested struct goes by its name
+#[derive(Debug, Clone)]
+enum FieldType {
+    Base(VarType),
+    Struct(String)
+}
+
 #[derive(Debug, Default)]
 struct Func {
     name: String,
     ret_type: VarType,
-    var_table: HashMap<String, Var>,
     param_list: Vec<VarType>,
+    // Struct params take one param per field, keyed by the position of the first one
+    struct_params: HashMap<usize, String>,
+    // Positions of ref params, they get the variable of the call instead of a copy
+    ref_params: HashSet<usize>,
     start_loc: usize,
     local_vars: (i32, i32, i32), // int, float, char
+    // Most local slots used at once, the variables of a block give their slots back when it ends
+    max_local_vars: (i32, i32, i32),
     temp_vars: (i32, i32, i32, i32) // int, float, char, bool
 }
 
+// Pending jumps of the loop being compiled
+#[derive(Debug, Default)]
+struct LoopContext {
+    break_jumps: Vec<usize>,
+    continue_jumps: Vec<usize>
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 enum Actions {
     Sum, // Done
@@ -63,9 +85,13 @@ enum Actions {
     Or, // Done
     ParentStart, // not necessary, only for compiler
     Print, // done
+    PrintFmt, // Done
+    PrintSpace, // Done
+    PrintLn, // Done
     Read,
     GotoF, // Done
     Goto, // Done
+    GotoTable, // Done
     Era, // Done
     EndFunc, // Done
     EndFuncS, // Done
@@ -73,14 +99,19 @@ enum Actions {
     Gosub, // Done
     Param, // Done
     Return, // Done
+    GetReturn, // Done
+    Error, // Done
     Center, // Done
     Forward, // Done
     Backward, // Done
     Left, // Done
     Right, // Done
-    // Point, CANT BE DONE
-    // Circle, CANT BE DONE
-    // Arc, CANT BE DONE
+    Dot, // Done
+    Circle, // Done
+    Arc, // Done
+    SetHeading, // Done
+    Speed, // Done
+    WriteText, // Done
     PenUp, // Done
     PenDown, // Done
     Color, // Done
@@ -91,6 +122,44 @@ enum Actions {
     FillColor, // Done
     StartFill, // Done
     EndFill, // Done
+    Sin, // Done
+    Cos, // Done
+    Sqrt, // Done
+    Pow, // Done
+    Abs, // Done
+    Floor, // Done
+    Round, // Done
+    Atan2, // Done
+    Min, // Done
+    Max, // Done
+    Pi, // Done
+    Random, // Done
+    RandomInt, // Done
+    Seed, // Done
+    GetX, // Done
+    GetY, // Done
+    Heading, // Done
+    IsPenDown, // Done
+    DistanceTo, // Done
+    NewTurtle, // Done
+    UseTurtle, // Done
+    Instant, // Done
+    Frame, // Done
+    Ord, // Done
+    Chr, // Done
+    ToInt, // Done
+    ToFloat, // Done
+    ToChar, // Done
+    ParamRef, // Done
+}
+
+// Built-in that runs as a single quadruple. Float params also take ints,
+// ret_type None returns int when every argument is int and float otherwise
+#[derive(Debug, Clone)]
+struct Builtin {
+    action: Actions,
+    params: Vec<VarType>,
+    ret_type: Option<VarType>
 }
 
 // #[derive(Debug)]
@@ -148,7 +217,7 @@ fn semantic_cube(action: Actions, l_op: &Option<Var>, r_op: &Option<Var>) -> Res
                 l_op == VarType::Float && r_op == VarType::Int { Ok(VarType::Float)}
             else if l_op == VarType::Char && r_op == VarType::Char { Ok(VarType::Char) }
             else {
-                return Err(format!("Incompatible types: {:?} and {:?}, {:?}", l_op, r_op, action));
+                return Err(format!("Incompatible types: {:?} and {:?}, {:?}. Convert it with int(x), float(x) or char(x)", l_op, r_op, action));
             }
         }
         Actions::Equal |
@@ -161,7 +230,7 @@ fn semantic_cube(action: Actions, l_op: &Option<Var>, r_op: &Option<Var>) -> Res
             l_op == VarType::Float && r_op == VarType::Float ||
             l_op == VarType::Int && r_op == VarType::Float ||
             l_op == VarType::Float && r_op == VarType::Int || 
-            l_op == VarType::Char && r_op == VarType::Char && (action == Actions::Equal || action == Actions::NotEqual)
+            l_op == VarType::Char && r_op == VarType::Char
             {
                 Ok(VarType::Bool)
             } else {
@@ -179,6 +248,43 @@ fn semantic_cube(action: Actions, l_op: &Option<Var>, r_op: &Option<Var>) -> Res
     }
 }
 
+// Functions that take a color as red, green, blue or as a single string
+const COLOR_FUNCTIONS: [&str; 3] = ["Color", "FillColor", "BackgroundColor"];
+
+// Turns a color name or a hex color ("#ff8800" or "#f80") into its red, green and blue channels
+fn parse_color(text: &str) -> Result<(u8, u8, u8), String> {
+    if let Some(hex) = text.strip_prefix('#') {
+        let digits: Vec<u8> = match hex.chars().map(|digit| digit.to_digit(16).map(|digit| digit as u8)).collect() {
+            Some(digits) => digits,
+            None => return Err(format!("Invalid hex color \"{}\".", text))
+        };
+        return match digits.as_slice() {
+            [r, g, b] => Ok((r * 17, g * 17, b * 17)),
+            [r1, r2, g1, g2, b1, b2] => Ok((r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2)),
+            _ => Err(format!("Hex colors must be like \"#ff8800\" or \"#f80\". Got: \"{}\"", text))
+        };
+    }
+
+    let color = match text.to_lowercase().as_str() {
+        "black" => (0, 0, 0),
+        "white" => (255, 255, 255),
+        "red" => (255, 0, 0),
+        "green" => (0, 128, 0),
+        "lime" => (0, 255, 0),
+        "blue" => (0, 0, 255),
+        "yellow" => (255, 255, 0),
+        "cyan" => (0, 255, 255),
+        "magenta" => (255, 0, 255),
+        "orange" => (255, 165, 0),
+        "purple" => (128, 0, 128),
+        "pink" => (255, 192, 203),
+        "brown" => (165, 42, 42),
+        "gray" | "grey" => (128, 128, 128),
+        _ => return Err(format!("Unknown color \"{}\".", text))
+    };
+    Ok(color)
+}
+
 #[derive(Debug)]
 struct Constant {
     Value: String,
@@ -186,28 +292,62 @@ struct Constant {
     Location: String
 }
 
+// The variable of an expression that is only that variable, like p.x or (p.x)
+fn single_variable(expresion: pest::iterators::Pair<Rule>) -> Option<pest::iterators::Pair<Rule>> {
+    let mut pair = expresion;
+    while pair.as_rule() != Rule::variable {
+        let mut inner = pair.into_inner();
+        pair = inner.next()?;
+        if inner.next().is_some() {
+            return None;
+        }
+    }
+    Some(pair)
+}
+
+// Canvas settings fixed at compile time and recorded in the obj file
+#[derive(Debug, Default)]
+struct CanvasSettings {
+    size: Option<(i32, i32)>,
+    title: Option<String>,
+    coordinates: Option<String>
+}
+
 #[derive(Default)]
 pub struct MMCompiler {
     program_name: String,
+    canvas_settings: CanvasSettings,
+    int_width: IntWidth,
     op_vec: Vec<Var>,
     oper_vec: Vec<Actions>,
     jump_vec: Vec<usize>,
+    loop_stack: Vec<LoopContext>,
     quadruples: Vec<Quadruple>,
     global_vars: HashMap<String, Var>,
     local_vars: HashMap<String, Var>,
+    // Struct declarations and the struct type of each struct variable. Their fields are
+    // variables named like p.x, with a location in the segment of their type
+    structs: HashMap<String, Vec<(String, FieldType)>>,
+    global_structs: HashMap<String, String>,
+    local_structs: HashMap<String, String>,
     constants: Vec<Constant>,
     function_table: HashMap<String, Func>,
     special_functions: HashMap<String, Vec<VarType>>,
+    builtin_functions: HashMap<String, Builtin>,
+    turtle_functions: HashSet<String>,
     current_func: String,
+    // Locals that hide a global are reported unless it is turned off
+    warn_shadowing: bool,
+    warnings: Vec<String>,
 
     // Memory position counters
     global_locs: (i32, i32, i32),
-    cte_locs: (i32, i32, i32)
+    cte_locs: (i32, i32, i32, i32)
 }
 
 impl MMCompiler {
     pub fn new() -> MMCompiler {
-        let mut new_comp: MMCompiler = Default::default();
+        let mut new_comp = MMCompiler { warn_shadowing: true, ..Default::default() };
 
         new_comp.special_functions.insert("Center".to_string(), vec![]);
         new_comp.special_functions.insert("Forward".to_string(), vec![VarType::Float]);
@@ -224,6 +364,56 @@ impl MMCompiler {
         new_comp.special_functions.insert("FillColor".to_string(), vec![VarType::Float, VarType::Float, VarType::Float]);
         new_comp.special_functions.insert("StartFill".to_string(), vec![]);
         new_comp.special_functions.insert("EndFill".to_string(), vec![]);
+        new_comp.special_functions.insert("Circle".to_string(), vec![VarType::Float]);
+        new_comp.special_functions.insert("Arc".to_string(), vec![VarType::Float, VarType::Float]);
+        new_comp.special_functions.insert("Dot".to_string(), vec![VarType::Float]);
+        new_comp.special_functions.insert("SetHeading".to_string(), vec![VarType::Float]);
+        new_comp.special_functions.insert("Speed".to_string(), vec![VarType::Int]);
+        new_comp.special_functions.insert("Instant".to_string(), vec![]);
+        new_comp.special_functions.insert("Frame".to_string(), vec![]);
+        // Takes a string literal, checked in process_call
+        new_comp.special_functions.insert("WriteText".to_string(), vec![]);
+        // Canvas settings, checked in process_canvas_setting
+        new_comp.special_functions.insert("CanvasSize".to_string(), vec![VarType::Int, VarType::Int]);
+        new_comp.special_functions.insert("Title".to_string(), vec![]);
+        new_comp.special_functions.insert("Coordinates".to_string(), vec![]);
+
+        // Math functions, angles are in radians
+        new_comp.builtin_functions.insert("sin".to_string(), Builtin { action: Actions::Sin, params: vec![VarType::Float], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("cos".to_string(), Builtin { action: Actions::Cos, params: vec![VarType::Float], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("sqrt".to_string(), Builtin { action: Actions::Sqrt, params: vec![VarType::Float], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("pow".to_string(), Builtin { action: Actions::Pow, params: vec![VarType::Float, VarType::Float], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("abs".to_string(), Builtin { action: Actions::Abs, params: vec![VarType::Float], ret_type: None });
+        new_comp.builtin_functions.insert("floor".to_string(), Builtin { action: Actions::Floor, params: vec![VarType::Float], ret_type: Some(VarType::Int) });
+        new_comp.builtin_functions.insert("round".to_string(), Builtin { action: Actions::Round, params: vec![VarType::Float], ret_type: Some(VarType::Int) });
+        new_comp.builtin_functions.insert("atan2".to_string(), Builtin { action: Actions::Atan2, params: vec![VarType::Float, VarType::Float], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("min".to_string(), Builtin { action: Actions::Min, params: vec![VarType::Float, VarType::Float], ret_type: None });
+        new_comp.builtin_functions.insert("max".to_string(), Builtin { action: Actions::Max, params: vec![VarType::Float, VarType::Float], ret_type: None });
+        new_comp.builtin_functions.insert("pi".to_string(), Builtin { action: Actions::Pi, params: vec![], ret_type: Some(VarType::Float) });
+
+        // Random numbers, randomInt includes both limits
+        new_comp.builtin_functions.insert("random".to_string(), Builtin { action: Actions::Random, params: vec![], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("randomInt".to_string(), Builtin { action: Actions::RandomInt, params: vec![VarType::Int, VarType::Int], ret_type: Some(VarType::Int) });
+        new_comp.builtin_functions.insert("seed".to_string(), Builtin { action: Actions::Seed, params: vec![VarType::Int], ret_type: Some(VarType::Void) });
+
+        // Unicode code point of a char and back
+        new_comp.builtin_functions.insert("ord".to_string(), Builtin { action: Actions::Ord, params: vec![VarType::Char], ret_type: Some(VarType::Int) });
+        new_comp.builtin_functions.insert("chr".to_string(), Builtin { action: Actions::Chr, params: vec![VarType::Int], ret_type: Some(VarType::Char) });
+
+        // Turtle state, heading is in degrees
+        new_comp.builtin_functions.insert("GetX".to_string(), Builtin { action: Actions::GetX, params: vec![], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("GetY".to_string(), Builtin { action: Actions::GetY, params: vec![], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("Heading".to_string(), Builtin { action: Actions::Heading, params: vec![], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("IsPenDown".to_string(), Builtin { action: Actions::IsPenDown, params: vec![], ret_type: Some(VarType::Bool) });
+        new_comp.builtin_functions.insert("DistanceTo".to_string(), Builtin { action: Actions::DistanceTo, params: vec![VarType::Float, VarType::Float], ret_type: Some(VarType::Float) });
+        new_comp.builtin_functions.insert("NewTurtle".to_string(), Builtin { action: Actions::NewTurtle, params: vec![], ret_type: Some(VarType::Int) });
+
+        // Functions that take a turtle handle as an optional first argument, Forward(t, 10.0)
+        for func_name in ["Center", "Forward", "Backward", "Left", "Right", "PenUp", "PenDown", "Color", "Size", "Clear",
+            "Position", "FillColor", "StartFill", "EndFill", "Circle", "Arc", "Dot", "SetHeading", "Speed", "Instant",
+            "GetX", "GetY", "Heading", "IsPenDown", "DistanceTo"].iter() {
+            new_comp.turtle_functions.insert(func_name.to_string());
+        }
 
         new_comp
     }
@@ -247,16 +437,257 @@ impl MMCompiler {
         }
     }
 
+    // Sets the destination of the jump at jump_pos
+    fn set_jump(&mut self, jump_pos: usize, pos: usize) {
+        if let Some(quad) = self.quadruples.get_mut(jump_pos) {
+            quad.out_op.Location = pos.to_string();
+        }
+    }
+
+    // Processes a condition and adds its pending GotoF
+    fn gen_condition_jump(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
+        self.process_expresion(data)?;
+        let cond_op = self.op_vec.pop().unwrap();
+        if cond_op.Type != VarType::Bool {
+            return Err(String::from("Variable debe ser boolean para generar if"));
+        }
+        self.jump_vec.push(self.quadruples.len());
+        self.gen_quad(Actions::GotoF, Some(cond_op), None, Var {Location: String::from(""), Type: VarType::Int});
+        Ok(())
+    }
+
+    // Fills the pending break and continue gotos of a finished loop
+    fn fill_loop_jumps(&mut self, loop_ctx: LoopContext, break_pos: usize, continue_pos: usize) {
+        for jump_pos in loop_ctx.break_jumps {
+            self.quadruples[jump_pos].out_op.Location = break_pos.to_string();
+        }
+        for jump_pos in loop_ctx.continue_jumps {
+            self.quadruples[jump_pos].out_op.Location = continue_pos.to_string();
+        }
+    }
+
+    // Ints of the program are 32 bits unless this is 64
+    pub fn set_int_width(&mut self, int_width: IntWidth) {
+        self.int_width = int_width;
+    }
+
+    // Warn when a local variable hides a global one
+    pub fn set_warn_shadowing(&mut self, warn_shadowing: bool) {
+        self.warn_shadowing = warn_shadowing;
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // Value of an int literal, it must fit in the int size of the program
+    fn int_literal(&self, literal: &str) -> Result<i64, String> {
+        match literal.parse::<i64>().ok().and_then(|val| self.int_width.fit(val)) {
+            Some(val) => Ok(val),
+            None if self.int_width == IntWidth::Bits32 => Err(format!("Int {} does not fit in 32 bits, compile with --int64 for bigger ints", literal)),
+            None => Err(format!("Int {} does not fit in 64 bits", literal))
+        }
+    }
+
+    // Adds a new constant to the constant table and returns its variable
+    fn new_constant(&mut self, value: &str, var_type: VarType) -> Var {
+        let const_loc = match var_type {
+            VarType::Int => {
+                let new_loc = BaseDirs::CteInt as i32 + self.cte_locs.0;
+                self.cte_locs.0 += 1;
+                new_loc
+            }
+            VarType::Float => {
+                let new_loc = BaseDirs::CteFloat as i32 + self.cte_locs.1;
+                self.cte_locs.1 += 1;
+                new_loc
+            }
+            VarType::Char => {
+                let new_loc = BaseDirs::CteChar as i32 + self.cte_locs.2;
+                self.cte_locs.2 += 1;
+                new_loc
+            }
+            VarType::String => {
+                let new_loc = BaseDirs::CteString as i32 + self.cte_locs.3;
+                self.cte_locs.3 += 1;
+                new_loc
+            }
+            _ => unreachable!()
+        };
+        self.constants.push(Constant { Location: const_loc.to_string(), Value: value.to_string(), Type: var_type });
+        Var { Location: const_loc.to_string(), Type: var_type }
+    }
+
+    // Returns the numeric value of a constant, if the variable is one
+    fn constant_value(&self, var: &Var) -> Option<f64> {
+        self.constants.iter()
+            .find(|cte| cte.Location == var.Location && (cte.Type == VarType::Int || cte.Type == VarType::Float))
+            .and_then(|cte| cte.Value.parse::<f64>().ok())
+    }
+
+    // Converts a value with int(x), float(x) or char(x), the same value if it already has the type
+    fn cast(&mut self, var: Var, target: VarType) -> Var {
+        if var.Type == target {
+            return var;
+        }
+        let action = match target {
+            VarType::Int => Actions::ToInt,
+            VarType::Float => Actions::ToFloat,
+            VarType::Char => Actions::ToChar,
+            _ => unreachable!()
+        };
+        let out_op = self.new_temp(target);
+        self.gen_quad(action, Some(var), None, out_op.clone());
+        out_op
+    }
+
+    // Value to store in a variable, parameter or return of the target type. Only int to float
+    // is implicit and it becomes a ToFloat, so the VM only ever copies values of the same type
+    fn promote(&mut self, var: Var, target: VarType) -> Result<Var, String> {
+        let target_var = Var { Location: String::new(), Type: target };
+        semantic_cube(Actions::Assign, &Some(target_var), &Some(var.clone()))?;
+        Ok(self.cast(var, target))
+    }
+
+    // Gets a new temporal location in the current function
+    fn new_temp(&mut self, var_type: VarType) -> Var {
+        let current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
+        let temp_loc = match var_type {
+            VarType::Int => {
+                let new_loc = BaseDirs::TempInt as i32 + current_func.temp_vars.0;
+                current_func.temp_vars.0 += 1;
+                new_loc
+            }
+            VarType::Float => {
+                let new_loc = BaseDirs::TempFloat as i32 + current_func.temp_vars.1;
+                current_func.temp_vars.1 += 1;
+                new_loc
+            }
+            VarType::Char => {
+                let new_loc = BaseDirs::TempChar as i32 + current_func.temp_vars.2;
+                current_func.temp_vars.2 += 1;
+                new_loc
+            }
+            VarType::Bool => {
+                let new_loc = BaseDirs::TempBool as i32 + current_func.temp_vars.3;
+                current_func.temp_vars.3 += 1;
+                new_loc
+            }
+            VarType::String | VarType::Void => unreachable!()
+        };
+        Var { Location: temp_loc.to_string(), Type: var_type }
+    }
+
     // Finds a variable in local or global contexts.
     fn find_var(&self, var_name: &str) -> Result<Var, String> {
-        // Get var from var_table and compare types
-        let curr_fun = self.function_table.get(self.current_func.as_str()).unwrap();
-        if let Some(var) = curr_fun.var_table.get(var_name) {
+        if let Some(var) = self.local_vars.get(var_name) {
             Ok(var.clone())
         } else if let Some(var) = self.global_vars.get(var_name) { 
             Ok(var.clone())
+        } else if let Some(struct_name) = self.find_struct(var_name) {
+            let first_field = &self.structs[&struct_name][0].0;
+            Err(format!("{} is a struct {}, use one of its fields like {}.{}", var_name, struct_name, var_name, first_field))
+        } else {
+            match var_name.rsplit_once('.').and_then(|(base, field)| self.find_struct(base).map(|struct_name| (struct_name, field))) {
+                Some((struct_name, field)) => Err(format!("Struct {} has no field {}", struct_name, field)),
+                None => Err(format!("Variable {} not declared yet.", var_name))
+            }
+        }
+    }
+
+    // Struct type of a variable or a field, like p or t.pos
+    fn find_struct(&self, var_name: &str) -> Option<String> {
+        let mut path = var_name.split('.');
+        let base = path.next().unwrap();
+        // Local variables hide global ones
+        let mut struct_name = match self.local_structs.get(base) {
+            Some(struct_name) => struct_name.clone(),
+            None if self.local_vars.contains_key(base) => return None,
+            None => self.global_structs.get(base)?.clone()
+        };
+        for field in path {
+            match self.structs[&struct_name].iter().find(|(name, _)| name == field) {
+                Some((_, FieldType::Struct(inner))) => struct_name = inner.clone(),
+                _ => return None
+            }
+        }
+        Some(struct_name)
+    }
+
+    // Fields of a struct with their basic type, nested structs are flattened like pos.x
+    fn struct_fields(&self, struct_name: &str) -> Vec<(String, VarType)> {
+        let mut fields: Vec<(String, VarType)> = Vec::new();
+        for (field_name, field_type) in self.structs[struct_name].iter() {
+            match field_type {
+                FieldType::Base(var_type) => fields.push((field_name.clone(), *var_type)),
+                FieldType::Struct(inner) => {
+                    for (inner_name, var_type) in self.struct_fields(inner) {
+                        fields.push((format!("{}.{}", field_name, inner_name), var_type));
+                    }
+                }
+            }
+        }
+        fields
+    }
+
+    // Basic type or struct of a declaration
+    fn parse_tipo(&self, tipo: &str) -> Result<FieldType, String> {
+        match tipo {
+            "int" => Ok(FieldType::Base(VarType::Int)),
+            "float" => Ok(FieldType::Base(VarType::Float)),
+            "char" => Ok(FieldType::Base(VarType::Char)),
+            _ if self.structs.contains_key(tipo) => Ok(FieldType::Struct(tipo.to_string())),
+            _ => Err(format!("Type {} is not declared, declare it with struct {} {{ ... }}", tipo, tipo))
+        }
+    }
+
+    // Declares a struct, its fields can be basic types or structs declared before it
+    fn process_struct(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
+        let mut fields = data.into_inner();
+        let struct_name = fields.next().unwrap().as_str();
+        if self.structs.contains_key(struct_name) {
+            return Err(format!("Struct {} has already been declared.", struct_name));
+        }
+
+        let mut struct_fields: Vec<(String, FieldType)> = Vec::new();
+        for campo in fields.filter(|field| field.as_rule() == Rule::campo) {
+            let mut campo = campo.into_inner();
+            let tipo = campo.next().unwrap().as_str();
+            let field_name = campo.next().unwrap().as_str();
+            if struct_fields.iter().any(|(name, _)| name == field_name) {
+                return Err(format!("Field {} is repeated in struct {}.", field_name, struct_name));
+            }
+            if tipo == struct_name {
+                return Err(format!("Struct {} cannot have a field of its own type.", struct_name));
+            }
+            struct_fields.push((field_name.to_string(), self.parse_tipo(tipo)?));
+        }
+        self.structs.insert(struct_name.to_string(), struct_fields);
+        Ok(())
+    }
+
+    // Location for a new global or local variable in the segment of its type
+    fn new_var(&mut self, var_type: VarType, scope_global: bool) -> Var {
+        let counters = if scope_global {
+            &mut self.global_locs
         } else {
-            return Err(String::from(format!("Variable {} not declared yet.", var_name)));
+            &mut self.function_table.get_mut(self.current_func.as_str()).unwrap().local_vars
+        };
+        let (base, counter) = match var_type {
+            VarType::Int => (if scope_global { BaseDirs::GlobalInt } else { BaseDirs::LocalInt }, &mut counters.0),
+            VarType::Float => (if scope_global { BaseDirs::GlobalFloat } else { BaseDirs::LocalFloat }, &mut counters.1),
+            VarType::Char => (if scope_global { BaseDirs::GlobalChar } else { BaseDirs::LocalChar }, &mut counters.2),
+            _ => unreachable!()
+        };
+        let new_loc = base as i32 + *counter;
+        *counter += 1;
+        Var { Location: new_loc.to_string(), Type: var_type }
+    }
+
+    // Warns when a local, a param or a block variable hides a global one
+    fn check_shadowing(&mut self, var_name: &str, line: usize) {
+        if self.warn_shadowing && (self.global_vars.contains_key(var_name) || self.global_structs.contains_key(var_name)) {
+            self.warnings.push(format!("Line {}: {} in module {} hides the global variable {}", line, var_name, self.current_func, var_name));
         }
     }
 
@@ -264,6 +695,7 @@ impl MMCompiler {
     fn process_vars(&mut self, data: pest::iterators::Pair<Rule>, scope_global: bool) -> Result<VarHash, String> {
         let mut current_tipo: &str = "";
         let mut var_map: VarHash = HashMap::new();
+        let mut struct_map: HashMap<String, String> = HashMap::new();
 
         for var in data.into_inner() {
             match var.as_rule() {
@@ -273,65 +705,41 @@ impl MMCompiler {
                 }
                 // Create each necessary variable
                 Rule::id => {
-                    let value = match current_tipo {
-                        // Create new int variable, return location
-                        "int" => { 
-                            let new_loc = if scope_global {
-                                let new_loc = BaseDirs::GlobalInt as i32 + self.global_locs.0;
-                                self.global_locs.0 += 1;
-                                new_loc
-                            } else {
-                                let mut current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
-                                let new_loc = BaseDirs::LocalInt as i32 + current_func.local_vars.0;
-                                current_func.local_vars.0 += 1;
-                                new_loc
-                            };
-
-                            (VarType::Int, new_loc)
+                    let var_name = var.as_str();
+                    if var_map.contains_key(var_name) || struct_map.contains_key(var_name) {
+                        return Err(format!("Variable {} has already been declared", var_name));
+                    }
+                    if !scope_global {
+                        if self.local_vars.contains_key(var_name) || self.local_structs.contains_key(var_name) {
+                            return Err(format!("Variable {} has already been declared in module {}.", var_name, self.current_func));
                         }
-                        // Create new float variable, return location
-                        "float" =>  {
-                            let new_loc = if scope_global {
-                                let new_loc = BaseDirs::GlobalFloat as i32 + self.global_locs.1;
-                                self.global_locs.1 += 1;
-                                new_loc
-                            } else {
-                                let mut current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
-                                let new_loc = BaseDirs::LocalFloat as i32 + current_func.local_vars.1;
-                                current_func.local_vars.1 += 1;
-                                new_loc
-                            };
-
-                            (VarType::Float, new_loc)
-                        },
-                        // Create new int variable, return location
-                        "char" => {
-                            let new_loc = if scope_global {
-                                let new_loc = BaseDirs::GlobalChar as i32 + self.global_locs.2;
-                                self.global_locs.2 += 1;
-                                new_loc
-                            } else {
-                                let mut current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
-                                let new_loc = BaseDirs::LocalChar as i32 + current_func.local_vars.2;
-                                current_func.local_vars.2 += 1;
-                                new_loc
-                            };
-
-                            (VarType::Char, new_loc)
-                        },
-                        _ => { unreachable!() }
-                    };
+                        self.check_shadowing(var_name, var.as_span().start_pos().line_col().0);
+                    }
 
-                    // Insert new variable into local or global variable maps
-                    match var_map.entry(var.as_str().to_string()) {
-                        Vacant(entry) => { entry.insert(Var { Location: value.1.to_string(), Type: value.0 }); }
-                        Occupied(_) => return Err(format!("Variable {} has already been declared", var.as_str()))
+                    match self.parse_tipo(current_tipo)? {
+                        FieldType::Base(var_type) => {
+                            let new_var = self.new_var(var_type, scope_global);
+                            var_map.insert(var_name.to_string(), new_var);
+                        }
+                        // Each field is a variable of its own, p.x and p.y
+                        FieldType::Struct(struct_name) => {
+                            for (field_name, var_type) in self.struct_fields(&struct_name) {
+                                let new_var = self.new_var(var_type, scope_global);
+                                var_map.insert(format!("{}.{}", var_name, field_name), new_var);
+                            }
+                            struct_map.insert(var_name.to_string(), struct_name);
+                        }
                     }
                 }
                 _ => {}
             }
         }
 
+        if scope_global {
+            self.global_structs.extend(struct_map);
+        } else {
+            self.local_structs.extend(struct_map);
+        }
         Ok(var_map)
     }
 
@@ -344,77 +752,10 @@ impl MMCompiler {
                     // Add "parenthesis" to give more precedence to functions :D
                     self.oper_vec.push(Actions::ParentStart);
                     let mut llamada_fields = field.into_inner();
-                    // Solve arguments, call expresion, assign from global to temporal and continue
 
                     let func_name = llamada_fields.next().unwrap().as_str();
-
-                    if !self.function_table.contains_key(func_name) {
-                        return Err(format!("Function {} is being called but has not been declared.", func_name).to_string());
-                    }
-
-                    let param_list = self.function_table.get(func_name).unwrap().param_list.clone();
-
-                    // Gen ERA quadruple
-                    self.gen_quad(Actions::Era, None, None, Var { Location: func_name.to_string(), Type: VarType::Void });
-
-                    // PRocess arguments of function call
-                    if let Some(args) = llamada_fields.next() {
-                        let mut param_count = 0;
-                        for (i, arg) in args.into_inner().enumerate() {
-                            self.process_expresion(arg)?;
-                            // POP from op_vec
-                            let param = self.op_vec.pop().unwrap().clone();
-
-                            if i >= param_list.len() {
-                                return Err(format!("Wrong number of arguments in function {}. Expected: {}. Got: {}", func_name, param_list.len(), param_count + 1));
-                            }
-
-                            if param_list[i] != param.Type {
-                                return Err(format!("Parameter {} in call of {} is of incompatible types. Expected: {:?}. Got: {:?}", i, func_name, param_list[i].clone(), param.Type.clone()));
-                            }
-
-                            self.gen_quad(Actions::Param, None, None, param);
-                            param_count += 1;
-                        }
-                        if param_count < param_list.len() {
-                            return Err(format!("Wrong number of arguments in function {}. Expected: {}. Got: {}", func_name, param_list.len(), param_count));
-                        }
-                    }
-
-                    // Gen GoSub quadruple
-                    self.gen_quad(Actions::Gosub, None, None, Var { Location: func_name.to_string(), Type: VarType::Void });
-
-                    // Left hand: global function location, out: temporal
-                    let lh_op = self.global_vars.get(func_name).unwrap().clone();
-                    
-                    let calling_func_type = self.function_table.get(func_name).unwrap().ret_type;
-
-                    let current_func = self.function_table.get_mut(&self.current_func).unwrap();
-
-                    // Get temporal location to save return value from function
-                    let temp_loc = match calling_func_type {
-                            VarType::Int => {
-                                let new_loc = BaseDirs::TempInt as i32 + current_func.temp_vars.0; 
-                                current_func.temp_vars.0 += 1; 
-                                new_loc 
-                            }
-                            VarType::Float => { 
-                                let new_loc = BaseDirs::TempFloat as i32 + current_func.temp_vars.1; 
-                                current_func.temp_vars.1 += 1; 
-                                new_loc }
-                            VarType::Char => { 
-                                let new_loc = BaseDirs::TempChar as i32 + current_func.temp_vars.2; 
-                                current_func.temp_vars.2 += 2;
-                                new_loc 
-                            }
-                            _ => { unreachable!() }
-                        };
-                    let new_loc = temp_loc;
-                    let ret_type = current_func.ret_type;
-                    drop(temp_loc);
-
-                    self.op_vec.push(Var { Type: calling_func_type, Location: new_loc.to_string() });
-                    self.gen_quad(Actions::Assign, Some(lh_op), None, Var { Type: ret_type, Location: new_loc.to_string() });
+                    let ret_val = self.process_call(func_name, llamada_fields.next(), true)?;
+                    self.op_vec.push(ret_val.unwrap());
 
                     self.oper_vec.pop();
                 },
@@ -429,14 +770,15 @@ impl MMCompiler {
                     let cte = field.into_inner().next().unwrap();
                     // here get type as_rule
                     let new_cte = match cte.as_rule() {
-                        Rule::id => { // get type from var table
+                        Rule::variable => { // get type from var table
                             let var_data = self.find_var(cte.as_str())?;
                             (var_data.Type, var_data.Location)
                         }
                         Rule::int => {
+                            let value = self.int_literal(cte.as_str())?;
                             let const_loc = BaseDirs::CteInt as i32 + self.cte_locs.0;
                             self.cte_locs.0 += 1;
-                            self.constants.push(Constant { Location: const_loc.to_string(),Value: cte.as_str().to_string(), Type: VarType::Int});
+                            self.constants.push(Constant { Location: const_loc.to_string(),Value: value.to_string(), Type: VarType::Int});
                             (VarType::Int, const_loc.to_string())
                         }
                         Rule::float => {
@@ -448,7 +790,7 @@ impl MMCompiler {
                         Rule::chars => {
                             let const_loc = BaseDirs::CteChar as i32 + self.cte_locs.2;
                             self.cte_locs.2 += 1;
-                            self.constants.push(Constant { Location: const_loc.to_string(), Value: cte.as_str().to_string().chars().nth(1).unwrap().to_string(), Type: VarType::Char });
+                            self.constants.push(Constant { Location: const_loc.to_string(), Value: unescape_char(cte.as_str())?.to_string(), Type: VarType::Char });
                             (VarType::Char, const_loc.to_string())
                         }
                         _=> { unreachable!() }
@@ -652,6 +994,294 @@ impl MMCompiler {
         Ok(())
     }
 
+    // CanvasSize, Title and Coordinates don't generate quadruples, they only take constants
+    fn process_canvas_setting(&mut self, func_name: &str, args: Option<pest::iterators::Pair<Rule>>) -> Result<(), String> {
+        let args: Vec<pest::iterators::Pair<Rule>> = match args {
+            Some(args) => args.into_inner().collect(),
+            None => Vec::new()
+        };
+
+        if func_name == "CanvasSize" {
+            if args.len() != 2 {
+                return Err(format!("CanvasSize expects a width and a height. Got {} arguments.", args.len()));
+            }
+            let mut size: Vec<i32> = Vec::new();
+            for arg in args {
+                if arg.as_rule() == Rule::string {
+                    return Err(String::from("CanvasSize expects int constants, like CanvasSize(640, 480)."));
+                }
+                self.process_expresion(arg)?;
+                let value = self.op_vec.pop().unwrap();
+                match self.constant_value(&value) {
+                    Some(value) if value >= 1.0 && value.fract() == 0.0 => size.push(value as i32),
+                    _ => return Err(String::from("CanvasSize expects positive int constants, like CanvasSize(640, 480)."))
+                }
+            }
+            self.canvas_settings.size = Some((size[0], size[1]));
+            return Ok(());
+        }
+
+        let text = match args.as_slice() {
+            [text] if text.as_rule() == Rule::string => unescape_string(text.as_str())?,
+            _ => return Err(format!("{} expects a single string, like {}(\"{}\").", func_name, func_name, if func_name == "Title" { "My drawing" } else { "corner" }))
+        };
+        if func_name == "Title" {
+            self.canvas_settings.title = Some(text);
+        } else if text == "center" || text == "corner" {
+            self.canvas_settings.coordinates = Some(text);
+        } else {
+            return Err(format!("Coordinates must be \"center\" or \"corner\". Got: \"{}\"", text));
+        }
+        Ok(())
+    }
+
+    // Separates the turtle handle from the arguments of a turtle function, when it is given
+    fn split_turtle_handle<'a>(&mut self, func_name: &str, params: usize, args: Option<pest::iterators::Pair<'a, Rule>>) -> Result<(Option<Var>, Vec<pest::iterators::Pair<'a, Rule>>), String> {
+        let mut args: Vec<pest::iterators::Pair<Rule>> = match args {
+            Some(args) => args.into_inner().collect(),
+            None => Vec::new()
+        };
+        // A color string counts as its three channels
+        let arg_count: usize = args.iter()
+            .map(|arg| if arg.as_rule() == Rule::string && COLOR_FUNCTIONS.contains(&func_name) { 3 } else { 1 })
+            .sum();
+        if !self.turtle_functions.contains(func_name) || arg_count != params + 1 || args[0].as_rule() == Rule::string {
+            return Ok((None, args));
+        }
+
+        self.process_expresion(args.remove(0))?;
+        let handle = self.op_vec.pop().unwrap();
+        if handle.Type != VarType::Int {
+            return Err(format!("Turtle handle in call of {} must be int. Got: {:?}", func_name, handle.Type));
+        }
+        Ok((Some(handle), args))
+    }
+
+    // Generates the quadruples of a function call, both as statute and inside expressions.
+    // Returns the temporal with the result when the call is used in an expression
+    fn process_call(&mut self, func_name: &str, args: Option<pest::iterators::Pair<Rule>>, in_expresion: bool) -> Result<Option<Var>, String> {
+        let cast_type = match func_name {
+            "int" => Some(VarType::Int),
+            "float" => Some(VarType::Float),
+            "char" => Some(VarType::Char),
+            _ => None
+        };
+        if let Some(target) = cast_type {
+            return self.process_cast(func_name, target, args).map(Some);
+        }
+
+        if let Some(builtin) = self.builtin_functions.get(func_name).cloned() {
+            return self.process_builtin_call(func_name, builtin, args, in_expresion);
+        }
+
+        let (params, struct_params, ref_params, ret_type, is_special) = if let Some(func) = self.function_table.get(func_name) {
+            (func.param_list.clone(), func.struct_params.clone(), func.ref_params.clone(), func.ret_type, false)
+        } else if let Some(params) = self.special_functions.get(func_name) {
+            (params.clone(), HashMap::new(), HashSet::new(), VarType::Void, true)
+        } else {
+            return Err(format!("Function {} is being called but has not been declared.", func_name));
+        };
+
+        if in_expresion && ret_type == VarType::Void {
+            return Err(format!("Function {} is void and cannot be used in an expression.", func_name));
+        }
+
+        if ["CanvasSize", "Title", "Coordinates"].contains(&func_name) {
+            self.process_canvas_setting(func_name, args)?;
+            return Ok(None);
+        }
+
+        if func_name == "WriteText" {
+            let label = args.and_then(|args| {
+                let mut args = args.into_inner();
+                match (args.next(), args.next()) {
+                    (Some(label), None) if label.as_rule() == Rule::string => Some(label),
+                    _ => None
+                }
+            });
+            let label = match label {
+                Some(label) => self.new_constant(&unescape_string(label.as_str())?, VarType::String),
+                None => return Err(String::from("WriteText expects a single string, like WriteText(\"label\")."))
+            };
+            self.gen_quad(Actions::WriteText, None, None, label);
+            return Ok(None);
+        }
+
+        let (turtle_handle, args) = self.split_turtle_handle(func_name, params.len(), args)?;
+
+        // en vm crear una segunda memoria
+        self.gen_quad(Actions::Era, None, None, Var { Location: func_name.to_string(), Type: VarType::Void });
+
+        let mut param_count = 0;
+        for arg in args.into_iter() {
+            let arg_params = if arg.as_rule() == Rule::string {
+                // Color("red") is the same as Color(255.0, 0.0, 0.0)
+                if !COLOR_FUNCTIONS.contains(&func_name) {
+                    return Err(format!("Parameter {} in call of {} cannot be a string.", param_count, func_name));
+                }
+                let (red, green, blue) = parse_color(&unescape_string(arg.as_str())?)?;
+                vec![
+                    self.new_constant(&format!("{:?}", red as f64), VarType::Float),
+                    self.new_constant(&format!("{:?}", green as f64), VarType::Float),
+                    self.new_constant(&format!("{:?}", blue as f64), VarType::Float)
+                ]
+            } else if let Some(struct_name) = struct_params.get(&param_count) {
+                // Structs go by value, a param for each field
+                let source = match single_variable(arg.clone()) {
+                    Some(variable) if self.find_struct(variable.as_str()).as_ref() == Some(struct_name) => variable.as_str(),
+                    _ => return Err(format!("Parameter {} in call of {} must be a struct {}. Got: {}", param_count, func_name, struct_name, arg.as_str().trim()))
+                };
+                self.struct_fields(struct_name).iter()
+                    .map(|(field_name, _)| self.find_var(&format!("{}.{}", source, field_name)))
+                    .collect::<Result<Vec<Var>, String>>()?
+            } else if ref_params.contains(&param_count) {
+                // A ref needs a variable, not the result of an expression
+                match single_variable(arg.clone()) {
+                    Some(variable) => vec![self.find_var(variable.as_str())?],
+                    None => return Err(format!("Parameter {} in call of {} is ref and needs a variable. Got: {}", param_count, func_name, arg.as_str().trim()))
+                }
+            } else {
+                self.process_expresion(arg)?;
+                // POP from op_vec
+                vec![self.op_vec.pop().unwrap()]
+            };
+
+            for param in arg_params {
+                if param_count >= params.len() {
+                    return Err(format!("Wrong number of arguments in function {}. Expected: {}. Got: {}", func_name, params.len(), param_count + 1));
+                }
+
+                if ref_params.contains(&param_count) {
+                    // No promotion, the function writes a value of its own type in the variable
+                    if param.Type != params[param_count] {
+                        return Err(format!("Parameter {} in call of {} is ref {:?} and needs a variable of that type. Got: {:?}", param_count, func_name, params[param_count], param.Type));
+                    }
+                    self.gen_quad(Actions::ParamRef, None, None, param);
+                    param_count += 1;
+                    continue;
+                }
+
+                let param = match self.promote(param.clone(), params[param_count]) {
+                    Ok(param) => param,
+                    Err(_) => return Err(format!("Parameter {} in call of {} is of incompatible types. Expected: {:?}. Got: {:?}", param_count, func_name, params[param_count], param.Type))
+                };
+
+                self.gen_quad(Actions::Param, None, None, param);
+                param_count += 1;
+            }
+        }
+        if param_count != params.len() {
+            return Err(format!("Wrong number of arguments in function {}. Expected: {}. Got: {}", func_name, params.len(), param_count));
+        }
+
+        if !is_special {
+            self.gen_quad(Actions::Gosub, None, None, Var { Location: func_name.to_string(), Type: VarType::Void });
+        } else {
+            let action = match func_name {
+                "Center" => Actions::Center,
+                "Forward" => Actions::Forward,
+                "Backward" => Actions::Backward,
+                "Left" => Actions::Left,
+                "Right" => Actions::Right,
+                "PenUp" => Actions::PenUp,
+                "PenDown" => Actions::PenDown,
+                "Color" => Actions::Color,
+                "Size" => Actions::Size,
+                "Clear" => Actions::Clear,
+                "Position" => Actions::Position,
+                "BackgroundColor" => Actions::BackgroundColor,
+                "FillColor" => Actions::FillColor,
+                "StartFill" => Actions::StartFill,
+                "EndFill" => Actions::EndFill,
+                "Circle" => Actions::Circle,
+                "Arc" => Actions::Arc,
+                "Dot" => Actions::Dot,
+                "SetHeading" => Actions::SetHeading,
+                "Speed" => Actions::Speed,
+                "Instant" => Actions::Instant,
+                "Frame" => Actions::Frame,
+                _ => return Err(format!("Unknown special function {}", func_name))
+            };
+            if let Some(handle) = turtle_handle {
+                self.gen_quad(Actions::UseTurtle, Some(handle), None, Var { Location: String::from(""), Type: VarType::Void });
+            }
+            self.gen_quad(action, None, None, Var { Location: func_name.to_string(), Type: VarType::Void });
+            self.gen_quad(Actions::EndFuncS, None, None, Var { Location: String::from(""), Type: VarType::Void });
+        }
+
+        // A statute discards the return value, expressions copy it from the return register to a temporal
+        if !in_expresion {
+            return Ok(None);
+        }
+        let temp = self.new_temp(ret_type);
+        self.gen_quad(Actions::GetReturn, None, None, temp.clone());
+        Ok(Some(temp))
+    }
+
+    // int(x), float(x) and char(x) take a single int, float or char
+    fn process_cast(&mut self, func_name: &str, target: VarType, args: Option<pest::iterators::Pair<Rule>>) -> Result<Var, String> {
+        let mut args: Vec<pest::iterators::Pair<Rule>> = args.map(|args| args.into_inner().collect()).unwrap_or_default();
+        if args.len() != 1 || args[0].as_rule() == Rule::string {
+            return Err(format!("{} expects a single int, float or char, like {}(x).", func_name, func_name));
+        }
+        self.process_expresion(args.remove(0))?;
+        let value = self.op_vec.pop().unwrap();
+        if value.Type != VarType::Int && value.Type != VarType::Float && value.Type != VarType::Char {
+            return Err(format!("Cannot convert {:?} with {}(x).", value.Type, func_name));
+        }
+        Ok(self.cast(value, target))
+    }
+
+    // Built-ins are a single quadruple that saves the result in a temporal
+    fn process_builtin_call(&mut self, func_name: &str, builtin: Builtin, args: Option<pest::iterators::Pair<Rule>>, in_expresion: bool) -> Result<Option<Var>, String> {
+        let (turtle_handle, args) = self.split_turtle_handle(func_name, builtin.params.len(), args)?;
+
+        let mut params: Vec<Var> = Vec::new();
+        for (i, arg) in args.into_iter().enumerate() {
+            if arg.as_rule() == Rule::string {
+                return Err(format!("Parameter {} in call of {} cannot be a string.", i, func_name));
+            }
+            self.process_expresion(arg)?;
+            let param = self.op_vec.pop().unwrap();
+            let expected = match builtin.params.get(i) {
+                Some(expected) => *expected,
+                None => return Err(format!("Wrong number of arguments in function {}. Expected: {}. Got: {}", func_name, builtin.params.len(), i + 1))
+            };
+            let compatible = param.Type == expected || expected == VarType::Float && param.Type == VarType::Int;
+            if !compatible {
+                return Err(format!("Parameter {} in call of {} is of incompatible types. Expected: {:?}. Got: {:?}", i, func_name, expected, param.Type));
+            }
+            params.push(param);
+        }
+        if params.len() != builtin.params.len() {
+            return Err(format!("Wrong number of arguments in function {}. Expected: {}. Got: {}", func_name, builtin.params.len(), params.len()));
+        }
+
+        let ret_type = match builtin.ret_type {
+            Some(ret_type) => ret_type,
+            None if params.iter().all(|param| param.Type == VarType::Int) => VarType::Int,
+            None => VarType::Float
+        };
+        if in_expresion && ret_type == VarType::Void {
+            return Err(format!("Function {} is void and cannot be used in an expression.", func_name));
+        }
+
+        let out_op = match ret_type {
+            VarType::Void => Var { Location: String::from(""), Type: VarType::Void },
+            _ => self.new_temp(ret_type)
+        };
+        if let Some(handle) = turtle_handle {
+            self.gen_quad(Actions::UseTurtle, Some(handle), None, Var { Location: String::from(""), Type: VarType::Void });
+        }
+        let mut params = params.into_iter();
+        self.gen_quad(builtin.action, params.next(), params.next(), out_op.clone());
+        if ret_type == VarType::Void {
+            Ok(None)
+        } else {
+            Ok(Some(out_op))
+        }
+    }
+
     // Process statutes
     fn process_statute(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
         let mut fields = data.into_inner();
@@ -661,33 +1291,51 @@ impl MMCompiler {
             Rule::asignacion => {
                 let mut assign_fields = estatuto.into_inner();
                 let var_name = assign_fields.next().unwrap().as_str();
-                self.process_expresion(assign_fields.next().unwrap())?;
-                
-                let lh_op = self.op_vec.pop();
+                let expresion = assign_fields.next().unwrap();
+
+                if let Some(struct_name) = self.find_struct(var_name) {
+                    // A whole struct is copied field by field
+                    let source = match single_variable(expresion.clone()) {
+                        Some(variable) if self.find_struct(variable.as_str()).as_ref() == Some(&struct_name) => variable.as_str(),
+                        _ => return Err(format!("Struct {} can only be assigned another struct {}. Got: {}", var_name, struct_name, expresion.as_str().trim()))
+                    };
+                    for (field_name, _) in self.struct_fields(&struct_name) {
+                        let from = self.find_var(&format!("{}.{}", source, field_name))?;
+                        let to = self.find_var(&format!("{}.{}", var_name, field_name))?;
+                        self.gen_quad(Actions::Assign, Some(from), None, to);
+                    }
+                } else {
+                    self.process_expresion(expresion)?;
 
-                let final_var = self.find_var(var_name)?;
+                    let lh_op = self.op_vec.pop().unwrap();
 
-                semantic_cube(Actions::Assign, &Some(final_var.clone()), &lh_op)?;
-                self.gen_quad(Actions::Assign, lh_op, None, final_var);
+                    let final_var = self.find_var(var_name)?;
+
+                    let lh_op = self.promote(lh_op, final_var.Type)?;
+                    self.gen_quad(Actions::Assign, Some(lh_op), None, final_var);
+                }
             },
             Rule::retorno => {
                 let mut return_fields = estatuto.into_inner();
+                let ret_type = self.function_table.get(self.current_func.as_str()).unwrap().ret_type;
                 if let Some(expresion) = return_fields.next() {
 
                     self.process_expresion(expresion)?;
                     let out_op = self.op_vec.pop().unwrap();
-                    let func: &Func = self.function_table.get(self.current_func.as_str()).unwrap();
-                    let return_loc = self.global_vars.get(self.current_func.as_str()).unwrap();
-                    if func.ret_type != out_op.Type {
-                        return Err("Return types are different.".to_string());
-                    }
-                    self.gen_quad(Actions::Return, Some(out_op), None, return_loc.clone());
+                    let out_op = match self.promote(out_op.clone(), ret_type) {
+                        Ok(out_op) => out_op,
+                        Err(_) => return Err(format!("Return types are different. Expected: {:?}. Got: {:?}", ret_type, out_op.Type))
+                    };
+                    // The value goes to the return register of the VM
+                    self.gen_quad(Actions::Return, Some(out_op), None, Var { Location: String::from(""), Type: ret_type });
+                } else if ret_type != VarType::Void {
+                    return Err(format!("Function {} must return a value of type {:?}.", self.current_func, ret_type));
                 }
 
                 self.gen_quad(Actions::EndFunc, None, None, Var { Location: String::from(""), Type: VarType::Void });
             },
             Rule::lectura => {
-                let read_fields = estatuto.into_inner();
+                let read_fields = estatuto.into_inner().filter(|field| field.as_rule() == Rule::variable);
                 for field in read_fields {
                     let var = self.find_var(field.as_str())?;
                     self.gen_quad(Actions::Read, None, None, var);
@@ -695,36 +1343,164 @@ impl MMCompiler {
             },
             Rule::decision => { // IF
                 let decision_fields = estatuto.into_inner();
+                let mut else_ifs = 0;
                 for field in decision_fields {
                 match field.as_rule() {
                     Rule::expresion => { 
-                        self.process_expresion(field)?;
-                        // Add GotoF
-                        let last_quad = self.quadruples.last().unwrap();
-                        if last_quad.out_op.Type != VarType::Bool {
-                            return Err(String::from("Variable debe ser boolean para generar for"));
-                        }
-                        self.jump_vec.push(self.quadruples.len());
-                        self.gen_quad(Actions::GotoF, Some(last_quad.out_op.clone()), None, Var {Location: String::from(""), Type: VarType::Int});
+                        self.gen_condition_jump(field)?;
                     }
-                    Rule::estatuto => {
-                        self.process_statute(field)?;
+                    Rule::bloque => {
+                        self.process_block(field)?;
+                    }
+                    Rule::sino_si => {
+                        // Previous branch jumps to the end, its GotoF lands on this condition
+                        self.fill_goto(self.quadruples.len() + 1);
+                        self.jump_vec.push(self.quadruples.len());
+                        self.gen_quad(Actions::Goto, None, None, Var {Location: String::from(""), Type: VarType::Int});
+                        else_ifs += 1;
+
+                        for field in field.into_inner() {
+                            match field.as_rule() {
+                                Rule::expresion => self.gen_condition_jump(field)?,
+                                Rule::bloque => self.process_block(field)?,
+                                _ => {}
+                            }
+                        }
                     }
                     Rule::elseIf => {
                         self.fill_goto(self.quadruples.len() + 1);
                         self.jump_vec.push(self.quadruples.len());
                         self.gen_quad(Actions::Goto, None, None, Var {Location: String::from(""), Type: VarType::Int});
                         // Add Goto to jump false if section
-                        for field in field.into_inner() {
+                        self.process_block(field.into_inner().next().unwrap())?;
+                    }
+                    _ => {}
+                }
+                }
+                // Last pending jump plus the end jump of every else if
+                for _ in 0..=else_ifs {
+                    self.fill_goto(self.quadruples.len());
+                }
+            },
+            Rule::seleccion => { // Switch
+                let mut seleccion_fields = estatuto.into_inner();
+                self.process_expresion(seleccion_fields.next().unwrap())?;
+                let selector = self.op_vec.pop().unwrap();
+                if selector.Type != VarType::Int && selector.Type != VarType::Char {
+                    return Err(format!("Switch expression must be int or char. Got: {:?}", selector.Type));
+                }
+
+                let mut cases: Vec<(String, pest::iterators::Pairs<Rule>)> = Vec::new();
+                let mut default_body: Option<pest::iterators::Pairs<Rule>> = None;
+                for field in seleccion_fields {
+                    match field.as_rule() {
+                        Rule::caso => {
+                            let mut caso_fields = field.into_inner();
+                            let cte = caso_fields.next().unwrap().into_inner().next().unwrap();
+                            let (value, cte_type) = match cte.as_rule() {
+                                Rule::int => (self.int_literal(cte.as_str())?.to_string(), VarType::Int),
+                                Rule::chars => (unescape_char(cte.as_str())?.to_string(), VarType::Char),
+                                _ => unreachable!()
+                            };
+                            if cte_type != selector.Type {
+                                return Err(format!("Case {} is of incompatible type. Expected: {:?}. Got: {:?}", cte.as_str(), selector.Type, cte_type));
+                            }
+                            if cases.iter().any(|(case_value, _)| *case_value == value) {
+                                return Err(format!("Case {} is repeated in switch", cte.as_str()));
+                            }
+                            cases.push((value, caso_fields));
+                        }
+                        Rule::defecto => {
+                            default_body = Some(field.into_inner());
+                        }
+                        _ => {}
+                    }
+                }
+
+                let mut end_jumps: Vec<usize> = Vec::new();
+
+                // Dense int cases jump through a table, anything else is a GotoF chain
+                // Int cases were checked when they were read, the range is computed in i128 so it can't overflow
+                let int_cases: Vec<i64> = if selector.Type == VarType::Int {
+                    cases.iter().map(|(value, _)| value.parse().unwrap()).collect()
+                } else {
+                    Vec::new()
+                };
+                let is_dense = match (int_cases.iter().min(), int_cases.iter().max()) {
+                    (Some(low), Some(high)) => cases.len() >= JUMP_TABLE_MIN_CASES && (*high as i128 - *low as i128) < (cases.len() * 2) as i128,
+                    _ => false
+                };
+
+                if is_dense {
+                    let low = *int_cases.iter().min().unwrap();
+                    let high = *int_cases.iter().max().unwrap();
+                    let low_cte = self.new_constant(&low.to_string(), VarType::Int);
+                    let high_cte = self.new_constant(&high.to_string(), VarType::Int);
+
+                    // Values outside of the table go to default
+                    let above_low = self.new_temp(VarType::Bool);
+                    let below_high = self.new_temp(VarType::Bool);
+                    let in_range = self.new_temp(VarType::Bool);
+                    self.gen_quad(Actions::MoreOrEqualThan, Some(selector.clone()), Some(low_cte.clone()), above_low.clone());
+                    self.gen_quad(Actions::LessOrEqualThan, Some(selector.clone()), Some(high_cte), below_high.clone());
+                    self.gen_quad(Actions::And, Some(above_low), Some(below_high), in_range.clone());
+                    let default_jump = self.quadruples.len();
+                    self.gen_quad(Actions::GotoF, Some(in_range), None, Var { Location: String::from(""), Type: VarType::Int });
+
+                    let offset = self.new_temp(VarType::Int);
+                    self.gen_quad(Actions::Sub, Some(selector), Some(low_cte), offset.clone());
+                    let table_start = self.quadruples.len() + 1;
+                    self.gen_quad(Actions::GotoTable, Some(offset), None, Var { Location: table_start.to_string(), Type: VarType::Int });
+                    for _ in low..=high {
+                        self.gen_quad(Actions::Goto, None, None, Var { Location: String::from(""), Type: VarType::Int });
+                    }
+
+                    for ((_, body), value) in cases.into_iter().zip(int_cases.iter()) {
+                        self.set_jump(table_start + (value - low) as usize, self.quadruples.len());
+                        for field in body {
                             self.process_statute(field)?;
                         }
+                        end_jumps.push(self.quadruples.len());
+                        self.gen_quad(Actions::Goto, None, None, Var { Location: String::from(""), Type: VarType::Int });
+                    }
+
+                    // Gaps in the table also go to default
+                    let default_pos = self.quadruples.len();
+                    self.set_jump(default_jump, default_pos);
+                    for value in low..=high {
+                        if !int_cases.contains(&value) {
+                            self.set_jump(table_start + (value - low) as usize, default_pos);
+                        }
+                    }
+                } else {
+                    for (value, body) in cases {
+                        let cte = self.new_constant(&value, selector.Type);
+                        let is_case = self.new_temp(VarType::Bool);
+                        self.gen_quad(Actions::Equal, Some(selector.clone()), Some(cte), is_case.clone());
+                        let next_case = self.quadruples.len();
+                        self.gen_quad(Actions::GotoF, Some(is_case), None, Var { Location: String::from(""), Type: VarType::Int });
+
+                        for field in body {
+                            self.process_statute(field)?;
+                        }
+                        end_jumps.push(self.quadruples.len());
+                        self.gen_quad(Actions::Goto, None, None, Var { Location: String::from(""), Type: VarType::Int });
+                        self.set_jump(next_case, self.quadruples.len());
                     }
-                    _ => {}
                 }
+
+                if let Some(body) = default_body {
+                    for field in body {
+                        self.process_statute(field)?;
+                    }
+                }
+
+                for jump_pos in end_jumps {
+                    self.set_jump(jump_pos, self.quadruples.len());
                 }
-                self.fill_goto(self.quadruples.len());
             },
             Rule::condicion => { // While
+                self.loop_stack.push(Default::default());
                 let condicion_fields = estatuto.into_inner();
                 for field in condicion_fields {
                     match  field.as_rule() {
@@ -741,8 +1517,8 @@ impl MMCompiler {
                             self.gen_quad(Actions::GotoF, lh_op, None, Var {Location: String::from(""), Type: VarType::Int});
 
                         },
-                        Rule::estatuto => {
-                            self.process_statute(field)?;
+                        Rule::bloque => {
+                            self.process_block(field)?;
                         },
                         _=> {}
                     }
@@ -750,28 +1526,31 @@ impl MMCompiler {
 
                 self.fill_goto(self.quadruples.len() + 1);
 
-                if let Some(jump_pos) = self.jump_vec.pop() {
-                    self.gen_quad(Actions::Goto, None, None, Var { Location: jump_pos.to_string(), Type: VarType::Int });
-                }
+                let jump_pos = self.jump_vec.pop().unwrap();
+                self.gen_quad(Actions::Goto, None, None, Var { Location: jump_pos.to_string(), Type: VarType::Int });
+
+                let loop_ctx = self.loop_stack.pop().unwrap();
+                self.fill_loop_jumps(loop_ctx, self.quadruples.len(), jump_pos);
             },
             Rule::no_condicion => { // For
-                let mut no_condicion_fields = estatuto.into_inner();
+                let mut no_condicion_fields = estatuto.into_inner().peekable();
 
                 let control_var = no_condicion_fields.next().unwrap();
                 let control_exp = no_condicion_fields.next().unwrap();
             
                 self.process_expresion(control_exp)?;
                 
-                let lh_op = self.op_vec.pop();
-                let control_var: Var = self.find_var(control_var.as_str()).unwrap();
+                let lh_op = self.op_vec.pop().unwrap();
+                let control_var: Var = self.find_var(control_var.as_str())?;
                 if control_var.Type != VarType::Int && control_var.Type != VarType::Float {
                     return Err(String::from("Variable inicial debe ser numerica para generar For"))
                 }
-                self.gen_quad(Actions::Assign, lh_op, None, control_var.clone());
+                let lh_op = self.promote(lh_op, control_var.Type)?;
+                self.gen_quad(Actions::Assign, Some(lh_op), None, control_var.clone());
 
                 let mut current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
 
-                let (VC, VF, temp_comp): (Var, Var, Var) = match control_var.Type {
+                let (VC, VF, VS): (Var, Var, Var) = match control_var.Type {
                     VarType::Int => {
                         let new_loc = BaseDirs::LocalInt as i32 + current_func.local_vars.0;
                         let new_loc2 = BaseDirs::LocalInt as i32 + current_func.local_vars.0 + 1;
@@ -779,8 +1558,8 @@ impl MMCompiler {
                         current_func.local_vars.0 += 3;
                         let VC = Var { Location: new_loc.to_string(), Type: VarType::Int };
                         let VF = Var { Location: new_loc2.to_string(), Type: VarType::Int };
-                        let temp_comp = Var { Location: new_loc3.to_string(), Type: VarType::Int };
-                        (VC, VF, temp_comp)
+                        let VS = Var { Location: new_loc3.to_string(), Type: VarType::Int };
+                        (VC, VF, VS)
 
                     }
                     VarType::Float => {
@@ -790,8 +1569,8 @@ impl MMCompiler {
                         current_func.local_vars.1 += 3;
                         let VC = Var { Location: new_loc.to_string(), Type: VarType::Float };
                         let VF = Var { Location: new_loc2.to_string(), Type: VarType::Float };
-                        let temp_comp = Var { Location: new_loc3.to_string(), Type: VarType::Float };
-                        (VC, VF, temp_comp)
+                        let VS = Var { Location: new_loc3.to_string(), Type: VarType::Float };
+                        (VC, VF, VS)
                     }
                     _ => unreachable!()
                 };
@@ -802,37 +1581,107 @@ impl MMCompiler {
 
                 self.process_expresion(cond_expr)?;
 
-                let cond_op = self.op_vec.pop();
+                let cond_op = self.op_vec.pop().unwrap();
+                let cond_op = self.promote(cond_op, VF.Type)?;
 
-                self.gen_quad(Actions::Assign, cond_op, None, VF.clone());
-
-                let comp_position = self.quadruples.len();
-                self.gen_quad(Actions::LessThan, Some(VC.clone()), Some(VF.clone()), temp_comp.clone());
+                self.gen_quad(Actions::Assign, Some(cond_op), None, VF.clone());
 
+                // Step defaults to 1 when not given
+                let step_op = match no_condicion_fields.peek().map(|field| field.as_rule()) {
+                    Some(Rule::step) => {
+                        let step_exp = no_condicion_fields.next().unwrap().into_inner().next().unwrap();
+                        self.process_expresion(step_exp)?;
+                        self.op_vec.pop().unwrap()
+                    }
+                    _ => self.new_constant("1", VarType::Int)
+                };
+                if step_op.Type != VarType::Int && step_op.Type != VarType::Float {
+                    return Err(String::from("Step debe ser numerico para generar For"));
+                }
+                // Known steps pick the comparison now, any other step is checked when the loop starts
+                let step_value = self.constant_value(&step_op);
+                let step_op = self.promote(step_op, VS.Type)?;
+                self.gen_quad(Actions::Assign, Some(step_op), None, VS.clone());
+
+                let temp_comp = self.new_temp(VarType::Bool);
+                let comp_position = match step_value {
+                    Some(value) => {
+                        if value == 0.0 {
+                            return Err(String::from("For loop step cannot be zero"));
+                        }
+                        let comp_position = self.quadruples.len();
+                        let comp_action = if value > 0.0 { Actions::LessOrEqualThan } else { Actions::MoreOrEqualThan };
+                        self.gen_quad(comp_action, Some(VC.clone()), Some(VF.clone()), temp_comp.clone());
+                        comp_position
+                    }
+                    None => {
+                        let zero = self.new_constant("0", VarType::Int);
+
+                        // Runtime error when the step is zero
+                        let is_zero = self.new_temp(VarType::Bool);
+                        self.gen_quad(Actions::Equal, Some(VS.clone()), Some(zero.clone()), is_zero.clone());
+                        self.gen_quad(Actions::GotoF, Some(is_zero), None, Var { Location: (self.quadruples.len() + 2).to_string(), Type: VarType::Void });
+                        self.gen_quad(Actions::Error, None, None, Var { Location: String::from("For loop step cannot be zero"), Type: VarType::Void });
+
+                        let is_positive = self.new_temp(VarType::Bool);
+                        self.gen_quad(Actions::MoreThan, Some(VS.clone()), Some(zero), is_positive.clone());
+
+                        // Ascending loops test <=, descending loops test >=
+                        let comp_position = self.quadruples.len();
+                        self.gen_quad(Actions::GotoF, Some(is_positive), None, Var { Location: (comp_position + 3).to_string(), Type: VarType::Void });
+                        self.gen_quad(Actions::LessOrEqualThan, Some(VC.clone()), Some(VF.clone()), temp_comp.clone());
+                        self.gen_quad(Actions::Goto, None, None, Var { Location: (comp_position + 4).to_string(), Type: VarType::Void });
+                        self.gen_quad(Actions::MoreOrEqualThan, Some(VC.clone()), Some(VF.clone()), temp_comp.clone());
+                        comp_position
+                    }
+                };
 
                 self.jump_vec.push(self.quadruples.len());
                 self.gen_quad(Actions::GotoF, Some(temp_comp.clone()), None, Var { Location: "".to_string(), Type: VarType::Void });
 
-                for estatute in no_condicion_fields.into_iter() {
-                    self.process_statute(estatute)?;
-                }
+                self.loop_stack.push(Default::default());
+                self.process_block(no_condicion_fields.next().unwrap())?;
 
-                let const_loc = BaseDirs::CteInt as i32 + self.cte_locs.0;
-                self.cte_locs.0 += 1;
-                self.constants.push(Constant { Location: const_loc.to_string(), Value: 1.to_string(), Type: VarType::Int });
-
-                self.gen_quad(Actions::Sum, Some(VC.clone()), Some(Var{ Location: const_loc.to_string(), Type: VarType::Int }), VC.clone());
+                let step_position = self.quadruples.len();
+                self.gen_quad(Actions::Sum, Some(VC.clone()), Some(VS), VC.clone());
 
                 self.gen_quad(Actions::Assign, Some(VC.clone()), None, control_var);
                 self.gen_quad(Actions::Goto, None, None, Var { Location: comp_position.to_string(), Type: VarType::Void });
                 self.fill_goto(self.quadruples.len());
+
+                let loop_ctx = self.loop_stack.pop().unwrap();
+                self.fill_loop_jumps(loop_ctx, self.quadruples.len(), step_position);
+            },
+            Rule::ruptura => { // Break
+                let jump_pos = self.quadruples.len();
+                match self.loop_stack.last_mut() {
+                    Some(loop_ctx) => loop_ctx.break_jumps.push(jump_pos),
+                    None => return Err(String::from("break can only be used inside a loop"))
+                }
+                self.gen_quad(Actions::Goto, None, None, Var { Location: String::from(""), Type: VarType::Void });
+            },
+            Rule::continuacion => { // Continue
+                let jump_pos = self.quadruples.len();
+                match self.loop_stack.last_mut() {
+                    Some(loop_ctx) => loop_ctx.continue_jumps.push(jump_pos),
+                    None => return Err(String::from("continue can only be used inside a loop"))
+                }
+                self.gen_quad(Actions::Goto, None, None, Var { Location: String::from(""), Type: VarType::Void });
             },
             Rule::escritura => {
-                let write_fields = estatuto.into_inner();
-                for field in write_fields {
+                let mut write_fields = estatuto.into_inner();
+                // write ends the line, writeNoLn doesn't
+                let new_line = write_fields.next().unwrap().as_str() == "write";
+                // The values of a write go in the same line, separated by a space
+                let write_fields = write_fields.filter(|field| field.as_rule() != Rule::comment);
+                for (i, field) in write_fields.enumerate() {
+                    if i > 0 {
+                        self.gen_quad(Actions::PrintSpace, None, None, Var { Location: String::from(""), Type: VarType::Void });
+                    }
                     match field.as_rule() {
                         Rule::string => {
-                            self.gen_quad(Actions::Print, None, None, Var { Location: field.as_str().to_string(), Type: VarType::Void });
+                            let text = self.new_constant(&unescape_string(field.as_str())?, VarType::String);
+                            self.gen_quad(Actions::Print, None, None, text);
                         }
                         Rule::expresion => {
                             self.process_expresion(field)?;
@@ -840,87 +1689,100 @@ impl MMCompiler {
                                 self.gen_quad(Actions::Print, None, None, out_op);
                             }
                         }
+                        Rule::formato => {
+                            self.process_format(field)?;
+                        }
                         _ => {}
                     }
                 }
+                if new_line {
+                    self.gen_quad(Actions::PrintLn, None, None, Var { Location: String::from(""), Type: VarType::Void });
+                }
             },
             Rule::llamada => {
                 let mut llamada_fields = estatuto.into_inner();
 
-                let func_name = llamada_fields.next().unwrap().as_str().to_string();
-
-                let mut params: Vec<VarType> = Default::default();
-                let mut is_special: bool = false;
-                if let Some(param_list) = self.function_table.get(func_name.as_str()) {
-                    params = param_list.param_list.clone();
-                    is_special = false;
-                } else if let Some(param_list) = self.special_functions.get(func_name.as_str()) {
-                    params = param_list.clone();
-                    is_special = true;
-                } else {
-                    return Err(format!("Unknown function {}", func_name).to_string());
-                }
+                let func_name = llamada_fields.next().unwrap().as_str();
+                self.process_call(func_name, llamada_fields.next(), false)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
 
-                // en vm crear una segunda memoria
-                self.gen_quad(Actions::Era, None, None, Var { Location: func_name.clone(), Type: VarType::Void });
+    // Compiles the block of an if, while or for. Its variables start at their default every time
+    // the block runs, and after it ends their names are gone and their slots are used again
+    fn process_block(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
+        let outer_vars = self.local_vars.clone();
+        let outer_structs = self.local_structs.clone();
+        let outer_counters = self.function_table[&self.current_func].local_vars;
 
-                let mut param_count = 0;
-                if let Some(args) = llamada_fields.next() {
-                    for (i, arg) in args.into_inner().enumerate() {
-                        self.process_expresion(arg)?;
-                        // POP from op_vec
-                        let param = self.op_vec.pop().unwrap().clone();
+        for field in data.into_inner() {
+            match field.as_rule() {
+                Rule::vars => {
+                    let vars = self.process_vars(field, false)?;
+                    let mut block_vars: Vec<(String, Var)> = vars.into_iter().collect();
+                    block_vars.sort_by(|(_, a), (_, b)| a.Location.cmp(&b.Location));
+                    for (var_name, var) in block_vars {
+                        let default = match var.Type {
+                            VarType::Int => self.new_constant("0", VarType::Int),
+                            VarType::Float => self.new_constant("0.0", VarType::Float),
+                            VarType::Char => self.new_constant("\0", VarType::Char),
+                            _ => unreachable!()
+                        };
+                        self.gen_quad(Actions::Assign, Some(default), None, var.clone());
+                        self.local_vars.insert(var_name, var);
+                    }
+                }
+                Rule::estatuto => self.process_statute(field)?,
+                _ => {}
+            }
+        }
 
-                        if i >= params.len() {
-                            return Err(format!("Wrong number of arguments {}. Expected: {}. Got: {}", func_name, params.len(), param_count + 1));
-                        }
+        let current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
+        let (used, peak) = (current_func.local_vars, current_func.max_local_vars);
+        current_func.max_local_vars = (used.0.max(peak.0), used.1.max(peak.1), used.2.max(peak.2));
+        current_func.local_vars = outer_counters;
+        self.local_vars = outer_vars;
+        self.local_structs = outer_structs;
+        Ok(())
+    }
 
-                        if params[i] != param.Type {
-                            return Err(format!("Parameter {} in call of {} is of incompatible types. Expected: {:?}. Got: {:?}", i, func_name, params[i].clone(), param.Type.clone()));
-                        }
+    // format("x = {} y = {:.2}", x, y) prints its text and each value with its placeholder spec
+    fn process_format(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
+        let mut format_fields = data.into_inner();
+        let format_str = unescape_string(format_fields.next().unwrap().as_str())?;
+        let pieces = parse_format(&format_str)?;
+        let mut values = format_fields;
+
+        let placeholders = pieces.iter().filter(|piece| matches!(piece, FormatPiece::Value(_))).count();
+        let value_count = values.clone().count();
+        if placeholders != value_count {
+            return Err(format!("format(\"{}\") has {} placeholders but got {} values.", format_str, placeholders, value_count));
+        }
 
-                        self.gen_quad(Actions::Param, None, None, param);
-                        param_count += 1;
-                    }
-                    if param_count < params.len() {
-                        return Err(format!("Wrong number of arguments {}. Expected: {}. Got: {}", func_name, params.len(), param_count));
-                    }
-                } else if param_count != params.len() {
-                    return Err(format!("Wrong number of arguments {}. Expected: {}. Got: {}", func_name, params.len(), param_count));
+        for piece in pieces {
+            match piece {
+                FormatPiece::Text(text) => {
+                    let text = self.new_constant(&text, VarType::String);
+                    self.gen_quad(Actions::Print, None, None, text);
                 }
-
-                if !is_special {
-                    self.gen_quad(Actions::Gosub, None, None, Var { Location: func_name.clone(), Type: VarType::Void });
-                } else {
-                    match func_name.as_str() {
-                        "Center" => self.gen_quad(Actions::Center, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "Forward" => self.gen_quad(Actions::Forward, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "Backward" => self.gen_quad(Actions::Backward, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "Left" => self.gen_quad(Actions::Left, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "Right" => self.gen_quad(Actions::Right, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        // "Point" => self.gen_quad(Actions::Point, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        // "Circle" => self.gen_quad(Actions::Circle, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        // "Arc" => self.gen_quad(Actions::Arc, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "PenUp" => self.gen_quad(Actions::PenUp, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "PenDown" => self.gen_quad(Actions::PenDown, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "Color" => self.gen_quad(Actions::Color, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "Size" => self.gen_quad(Actions::Size, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "Clear" => self.gen_quad(Actions::Clear, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "BackgroundColor" => self.gen_quad(Actions::BackgroundColor, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "FillColor" => self.gen_quad(Actions::FillColor, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "StartFill" => self.gen_quad(Actions::StartFill, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        "EndFill" => self.gen_quad(Actions::EndFill, None, None, Var { Location: func_name.clone(), Type: VarType::Void }),
-                        _ => error!("Unknown special function {}", func_name.clone())
+                FormatPiece::Value(spec) => {
+                    self.process_expresion(values.next().unwrap())?;
+                    let value = self.op_vec.pop().unwrap();
+                    if spec.is_empty() {
+                        self.gen_quad(Actions::Print, None, None, value);
+                    } else {
+                        self.gen_quad(Actions::PrintFmt, Some(value), None, Var { Location: spec, Type: VarType::Void });
                     }
-                    self.gen_quad(Actions::EndFuncS, None, None, Var { Location: String::from(""), Type: VarType::Void })
                 }
             }
-            _ => {}
         }
         Ok(())
     }
 
-    fn process_function(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
+    // Adds the signature of a function to the function table, before any body is compiled
+    fn declare_function(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
         let mut fields = data.into_inner();
         let return_type: &str = fields.next().unwrap().as_str();
         let func_return_type = match return_type {
@@ -930,37 +1792,45 @@ impl MMCompiler {
             "void" | &_ => VarType::Void,
         };
         let func_name = fields.next().unwrap().as_str();
-        self.current_func = func_name.to_string();
 
-        match func_return_type {
-            VarType::Int => {
-                let new_loc = BaseDirs::GlobalInt as i32 + self.global_locs.0;
-                self.global_locs.0 += 1;
-                self.global_vars.insert(func_name.to_string(), Var { Type: VarType::Int, Location: new_loc.to_string() });
-            }
-            VarType::Float => {
-                let new_loc = BaseDirs::GlobalFloat as i32 + self.global_locs.1;
-                self.global_locs.1 += 1;
-                self.global_vars.insert(func_name.to_string(), Var { Type: VarType::Float, Location: new_loc.to_string() });
-            }
-            VarType::Char => {
-                let new_loc = BaseDirs::GlobalChar as i32 + self.global_locs.2;
-                self.global_locs.2 += 1;
-                self.global_vars.insert(func_name.to_string(), Var { Type: VarType::Char, Location: new_loc.to_string() });
-            }
-            _ => {}
+        if self.global_vars.contains_key(func_name) || self.global_structs.contains_key(func_name) {
+            return Err(format!("Function {} has the same name as a global variable.", func_name));
+        }
+        if self.special_functions.contains_key(func_name) || self.builtin_functions.contains_key(func_name) {
+            return Err(format!("Function {} has the same name as a built-in function.", func_name));
         }
 
-        // debug!("Processing function {}", func_name);
+        // Types of the parameters, in order, a struct adds the types of its fields
+        let mut param_list: Vec<VarType> = Vec::new();
+        let mut struct_params: HashMap<usize, String> = HashMap::new();
+        let mut ref_params: HashSet<usize> = HashSet::new();
+        if let Some(args) = fields.next().filter(|field| field.as_rule() == Rule::args) {
+            for arg in args.into_inner() {
+                let mut arg = arg.into_inner().peekable();
+                let is_ref = arg.next_if(|field| field.as_rule() == Rule::por_referencia).is_some();
+                let first_param = param_list.len();
+                match self.parse_tipo(arg.next().unwrap().as_str())? {
+                    FieldType::Base(arg_type) => param_list.push(arg_type),
+                    FieldType::Struct(struct_name) => {
+                        let fields = self.struct_fields(&struct_name);
+                        struct_params.insert(param_list.len(), struct_name);
+                        param_list.extend(fields.into_iter().map(|(_, field_type)| field_type));
+                    }
+                }
+                // Every field of a ref struct is a ref
+                if is_ref {
+                    ref_params.extend(first_param..param_list.len());
+                }
+            }
+        }
 
         let new_func = Func {
             name: func_name.to_string(),
-            ret_type: func_return_type.clone(),
-            var_table: HashMap::new(),
-            param_list: Vec::new(),
-            start_loc: self.quadruples.len(),
-            local_vars: (0,0,0),
-            temp_vars: (0,0,0,0)
+            ret_type: func_return_type,
+            param_list,
+            struct_params,
+            ref_params,
+            ..Default::default()
         };
 
         match self.function_table.entry(func_name.to_string())  {
@@ -968,43 +1838,50 @@ impl MMCompiler {
             Occupied(_) => return Err(format!("Function {} has already been declared.", func_name))
         };
 
+        Ok(())
+    }
+
+    fn process_function(&mut self, data: pest::iterators::Pair<Rule>) -> Result<(), String> {
+        let mut fields = data.into_inner();
+        // Return type and params were already read by declare_function
+        fields.next();
+        let func_name = fields.next().unwrap().as_str();
+        self.current_func = func_name.to_string();
+
+        // debug!("Processing function {}", func_name);
+
+        self.function_table.get_mut(func_name).unwrap().start_loc = self.quadruples.len();
+
         debug!("Funcs: {:?}", self.function_table);
 
         for field in fields {
             match field.as_rule() {
                 Rule::args => { 
-                    let mut current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
-
-                    // Process args and add to args vec
+                    // Process args and add to args vec, in the same order as the call sends them
                     let args = field.into_inner();
                     for arg in args {
-                        let mut arg = arg.into_inner();
+                        // A ref param is used like any other variable, the VM reads and writes through it
+                        let mut arg = arg.into_inner().filter(|field| field.as_rule() != Rule::por_referencia);
                         let arg_type = arg.next().unwrap();
-                        let arg_id = arg.next().unwrap().as_str();
-                        let arg_data = match arg_type.as_str() {
-                            "int" => { 
-                                let new_loc = BaseDirs::LocalInt as i32 + current_func.local_vars.0; 
-                                current_func.local_vars.0 += 1; 
-                                (VarType::Int, new_loc) 
-                            }
-                            "float" => { 
-                                let new_loc = BaseDirs::LocalFloat as i32 + current_func.local_vars.1; 
-                                current_func.local_vars.1 += 1; 
-                                (VarType::Float, new_loc) 
+                        let arg_id = arg.next().unwrap();
+                        let line = arg_id.as_span().start_pos().line_col().0;
+                        let arg_id = arg_id.as_str();
+                        if self.local_vars.contains_key(arg_id) || self.local_structs.contains_key(arg_id) {
+                            return Err(format!("Param {} has already been declared.", arg_id));
+                        }
+                        self.check_shadowing(arg_id, line);
+                        match self.parse_tipo(arg_type.as_str())? {
+                            FieldType::Base(var_type) => {
+                                let new_var = self.new_var(var_type, false);
+                                self.local_vars.insert(arg_id.to_string(), new_var);
                             }
-                            "char" => { 
-                                let new_loc = BaseDirs::LocalChar as i32 + current_func.local_vars.2; 
-                                current_func.local_vars.2 += 1; 
-                                (VarType::Char, new_loc) 
+                            FieldType::Struct(struct_name) => {
+                                for (field_name, var_type) in self.struct_fields(&struct_name) {
+                                    let new_var = self.new_var(var_type, false);
+                                    self.local_vars.insert(format!("{}.{}", arg_id, field_name), new_var);
+                                }
+                                self.local_structs.insert(arg_id.to_string(), struct_name);
                             }
-                            &_ => unreachable!()
-                        };
-                        match self.local_vars.entry(arg_id.to_string()) {
-                            Vacant(entry) => {
-                                entry.insert(Var { Type: arg_data.0, Location: arg_data.1.to_string() });
-                                current_func.param_list.push(arg_data.0);
-                            },
-                            Occupied(_) => return Err(format!("Param {} has already been declared.", arg_id))
                         }
                     }
                 }
@@ -1020,10 +1897,6 @@ impl MMCompiler {
                     // Insert local variables into function declaration
                 }
                 Rule::estatuto => {
-                    let mut current_func = self.function_table.get_mut(self.current_func.as_str()).unwrap();
-                    current_func.var_table = self.local_vars.clone();
-
-                    // Process statutes
                     self.process_statute(field)?;
                 }
                 _ => {}
@@ -1031,8 +1904,14 @@ impl MMCompiler {
         }
         self.gen_quad(Actions::EndFunc, None, None, Var { Location: String::from(""), Type: VarType::Void });
 
+        // The frame needs room for the largest set of blocks open at once
+        let current_func = self.function_table.get_mut(func_name).unwrap();
+        let (used, peak) = (current_func.local_vars, current_func.max_local_vars);
+        current_func.local_vars = (used.0.max(peak.0), used.1.max(peak.1), used.2.max(peak.2));
+
         // Reset local variables
         self.local_vars.clear();
+        self.local_structs.clear();
         
         // reset all counters
         Ok(())
@@ -1052,17 +1931,32 @@ impl MMCompiler {
             // self.gen_quad(Actions::Era, None, None, Var { Location: "main".to_string(), Type: VarType::Void });
             self.gen_quad(Actions::Goto, None, None, Var{ Location: "".to_string(), Type: VarType::Void});
 
+            let fields: Vec<pest::iterators::Pair<Rule>> = fields.collect();
+
+            for field in fields.iter() {
+                if field.as_rule() == Rule::estructura {
+                    self.process_struct(field.clone())?;
+                }
+            }
+
+            for field in fields.iter() {
+                if field.as_rule() == Rule::vars {
+                    let var_map = self.process_vars(field.clone(), true)?;
+                    self.global_vars = var_map;
+                }
+            }
+
+            // Declare every function first so they can be called in any order
+            for field in fields.iter() {
+                if field.as_rule() == Rule::funciones {
+                    self.declare_function(field.clone())?;
+                }
+            }
+
             for field in fields {
-                match field.as_rule() {
-                    Rule::vars => {
-                        let var_map = self.process_vars(field, true)?;
-                        self.global_vars = var_map;
-                    }
-                    Rule::funciones => {
-                        self.process_function(field)?;
-                    }
-                    _ => {}
-                } 
+                if field.as_rule() == Rule::funciones {
+                    self.process_function(field)?;
+                }
             }
         }
             _ => { 
@@ -1089,24 +1983,43 @@ impl MMCompiler {
         Ok(())
     }
 
-    pub fn process_file(&mut self, file_name: &str) {
-        let file = fs::read_to_string(file_name).expect("Cannot read file");
-
-        let data = MMIParser::parse(Rule::file, &file).expect("unsuccessful parse").next().unwrap();
+    // Compiles a program that is already in memory
+    pub fn process_str(&mut self, source: &str) -> Result<(), String> {
+        let data = match MMIParser::parse(Rule::file, source) {
+            Ok(mut data) => data.next().unwrap(),
+            Err(err) => return Err(format!("Syntax error:\n{}", err))
+        };
 
-        self.process_rules(data.into_inner().next().unwrap().clone()).unwrap();
+        self.process_rules(data.into_inner().next().unwrap())
     }
 
-    pub fn write_obj_file(&self, file_name: &str) -> std::io::Result<()> {
-        let mut file = File::create(file_name)?;
+    // Writes the obj contents to any writer, like a file or a buffer in memory
+    pub fn write_obj<W: Write>(&self, file: &mut W) -> std::io::Result<()> {
+        writeln!(file, "P {}", self.program_name)?;
 
-        info!("Output file: {}", file_name);
+        // Size of the ints, the VM checks every int result against it
+        writeln!(file, "S ints {}", self.int_width.bits())?;
 
-        writeln!(file, "P {}", self.program_name);
+        // Canvas settings: S size w h, S coordinates mode, S title "text"
+        if let Some((width, height)) = self.canvas_settings.size {
+            writeln!(file, "S size {} {}", width, height)?;
+        }
+        if let Some(coordinates) = &self.canvas_settings.coordinates {
+            writeln!(file, "S coordinates {}", coordinates)?;
+        }
+        if let Some(title) = &self.canvas_settings.title {
+            writeln!(file, "S title {}", escape_string(title))?;
+        }
 
         // Write a &str in the file (ignoring the result).
         for constant in self.constants.iter() {
-            writeln!(file, "C {} {} {:?}", constant.Value, constant.Location, constant.Type)?;
+            // Strings and chars are quoted and escaped so they keep their spaces and stay in one line
+            let value = match constant.Type {
+                VarType::String => escape_string(&constant.Value),
+                VarType::Char => escape_char(constant.Value.chars().next().unwrap()),
+                _ => constant.Value.clone()
+            };
+            writeln!(file, "C {} {} {:?}", value, constant.Location, constant.Type)?;
         }
 
         writeln!(file, "G {} {} {}", self.global_locs.0, self.global_locs.1, self.global_locs.2)?;
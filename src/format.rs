@@ -0,0 +1,184 @@
+use crate::memory::VarValue;
+
+// Placeholder of format(), "{:>8.2}" is right aligned, 8 wide and with 2 decimals
+#[derive(Debug, Default, PartialEq)]
+pub struct FormatSpec {
+    pub align_left: bool,
+    pub zero_pad: bool,
+    pub width: usize,
+    pub precision: Option<usize>
+}
+
+// Pieces of a format string, the text between placeholders and the placeholders
+#[derive(Debug, PartialEq)]
+pub enum FormatPiece {
+    Text(String),
+    // The spec as written, empty for {}
+    Value(String)
+}
+
+impl FormatSpec {
+    // Parses what goes after the ':' of a placeholder: [<|>][0][width][.precision]
+    pub fn parse(spec: &str) -> Result<FormatSpec, String> {
+        let mut format_spec: FormatSpec = Default::default();
+        let mut rest = spec;
+        if let Some(after) = rest.strip_prefix('<') {
+            format_spec.align_left = true;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('>') {
+            rest = after;
+        }
+        if let Some(after) = rest.strip_prefix('0') {
+            format_spec.zero_pad = true;
+            rest = after;
+        }
+
+        let (width, precision) = match rest.find('.') {
+            Some(dot) => (&rest[..dot], Some(&rest[dot + 1..])),
+            None => (rest, None)
+        };
+        if !width.is_empty() {
+            format_spec.width = width.parse().map_err(|_| format!("Invalid width in format placeholder {{:{}}}", spec))?;
+        }
+        if let Some(precision) = precision {
+            format_spec.precision = Some(precision.parse().map_err(|_| format!("Invalid precision in format placeholder {{:{}}}", spec))?);
+        }
+        Ok(format_spec)
+    }
+
+    pub fn format(&self, value: &VarValue) -> String {
+        let text = match (value, self.precision) {
+            (VarValue::Float(val), Some(precision)) => format!("{:.*}", precision, val),
+            (VarValue::Int(val), Some(precision)) => format!("{:.*}", precision, *val as f64),
+            _ => value.to_string()
+        };
+        let is_number = matches!(value, VarValue::Int(_) | VarValue::Float(_));
+
+        if self.zero_pad && is_number {
+            // The sign goes before the zeros, like -007
+            let (sign, digits) = match text.strip_prefix('-') {
+                Some(digits) => ("-", digits),
+                None => ("", text.as_str())
+            };
+            format!("{}{:0>width$}", sign, digits, width = self.width.saturating_sub(sign.len()))
+        } else if self.align_left || !is_number {
+            format!("{:<width$}", text, width = self.width)
+        } else {
+            format!("{:>width$}", text, width = self.width)
+        }
+    }
+}
+
+// Splits "x = {} y = {:.2}" into its text and its placeholders, {{ and }} are literal braces
+pub fn parse_format(format_str: &str) -> Result<Vec<FormatPiece>, String> {
+    let mut pieces: Vec<FormatPiece> = Vec::new();
+    let mut text = String::new();
+    let mut chars = format_str.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(format!("Unclosed placeholder in format string \"{}\"", format_str))
+                    }
+                }
+                let spec = match placeholder.strip_prefix(':') {
+                    Some(spec) => spec.to_string(),
+                    None if placeholder.is_empty() => String::new(),
+                    None => return Err(format!("Invalid placeholder {{{}}} in format string, use {{}} or {{:spec}}", placeholder))
+                };
+                FormatSpec::parse(&spec)?;
+                if !text.is_empty() {
+                    pieces.push(FormatPiece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(FormatPiece::Value(spec));
+            }
+            '}' => return Err(format!("Unmatched }} in format string \"{}\", use }}}} for a brace", format_str)),
+            _ => text.push(c)
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(FormatPiece::Text(text));
+    }
+    Ok(pieces)
+}
+
+// Contents of a string literal, "a\tb" becomes a, a tab and b
+pub fn unescape_string(literal: &str) -> Result<String, String> {
+    let inner = literal.strip_prefix('"').and_then(|inner| inner.strip_suffix('"')).unwrap_or(literal);
+    unescape(inner, literal)
+}
+
+// Value of a char literal, '\n' is a single newline
+pub fn unescape_char(literal: &str) -> Result<char, String> {
+    let inner = literal.strip_prefix('\'').and_then(|inner| inner.strip_suffix('\'')).unwrap_or(literal);
+    let text = unescape(inner, literal)?;
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(format!("Char literal {} must have exactly one character", literal))
+    }
+}
+
+fn unescape(inner: &str, literal: &str) -> Result<String, String> {
+    let mut text = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some('r') => text.push('\r'),
+            Some('0') => text.push('\0'),
+            Some('\\') => text.push('\\'),
+            Some('"') => text.push('"'),
+            Some('\'') => text.push('\''),
+            Some(other) => return Err(format!("Unknown escape \\{} in {}", other, literal)),
+            None => return Err(format!("Unfinished escape at the end of {}", literal))
+        }
+    }
+    Ok(text)
+}
+
+// Quoted string with escapes, it fits in one line of the obj file
+pub fn escape_string(text: &str) -> String {
+    format!("\"{}\"", escape(text.chars(), '"'))
+}
+
+// Char literal with escapes, like '\n' or '\''
+pub fn escape_char(c: char) -> String {
+    format!("'{}'", escape(std::iter::once(c), '\''))
+}
+
+fn escape(chars: impl Iterator<Item = char>, quote: char) -> String {
+    let mut literal = String::new();
+    for c in chars {
+        match c {
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            '\r' => literal.push_str("\\r"),
+            '\0' => literal.push_str("\\0"),
+            '\\' => literal.push_str("\\\\"),
+            _ if c == quote => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            _ => literal.push(c)
+        }
+    }
+    literal
+}
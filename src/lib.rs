@@ -0,0 +1,153 @@
+#[macro_use]
+extern crate pest_derive;
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+
+pub mod canvas;
+mod compiler;
+mod format;
+mod memory;
+mod vm;
+
+use canvas::Canvas;
+
+// Errors found while compiling a program, and the warnings found before them
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.errors.join("\n"))
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+// A compiled program, the same contents as a .obj file
+#[derive(Debug, Clone)]
+pub struct Program {
+    obj: String,
+    warnings: Vec<String>
+}
+
+impl Program {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.obj.as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, String> {
+        let obj = String::from_utf8(bytes.to_vec()).map_err(|_| String::from("Program is not valid utf-8"))?;
+        if !obj.starts_with("P ") {
+            return Err(String::from("Program must start with its name, is it a .obj file?"));
+        }
+        // Loaded once here so a broken program is an error now and not a crash when it runs
+        vm::VM::new().load(obj.as_bytes())?;
+        Ok(Program { obj, warnings: Vec::new() })
+    }
+
+    pub fn name(&self) -> &str {
+        self.obj.lines().next().unwrap_or("").trim_start_matches("P ")
+    }
+
+    // Warnings of the compiler, a program read from bytes has none
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+// Options of compile_str_with, the default is what compile_str uses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompileOptions {
+    // 64 bit ints instead of 32 bit ones, the program records it for the VM
+    pub int64: bool,
+    // No warning when a local variable hides a global one
+    pub allow_shadowing: bool
+}
+
+// Compiles the source of a MeMyself program
+pub fn compile_str(source: &str) -> Result<Program, Diagnostics> {
+    compile_str_with(source, CompileOptions::default())
+}
+
+pub fn compile_str_with(source: &str, options: CompileOptions) -> Result<Program, Diagnostics> {
+    let mut compiler = compiler::MMCompiler::new();
+    if options.int64 {
+        compiler.set_int_width(memory::IntWidth::Bits64);
+    }
+    compiler.set_warn_shadowing(!options.allow_shadowing);
+    let warnings = |compiler: &compiler::MMCompiler| compiler.warnings().to_vec();
+    compiler.process_str(source).map_err(|err| Diagnostics { errors: vec![err], warnings: warnings(&compiler) })?;
+
+    let mut obj: Vec<u8> = Vec::new();
+    compiler.write_obj(&mut obj).map_err(|err| Diagnostics { errors: vec![err.to_string()], warnings: warnings(&compiler) })?;
+    Ok(Program { obj: String::from_utf8(obj).unwrap(), warnings: warnings(&compiler) })
+}
+
+// Runs a program, by default with stdin, stdout and a turtle window.
+//
+// Vm::new(program).with_io(input, &mut output).with_canvas(SvgCanvas::new("frames")).run()
+pub struct Vm<'a> {
+    machine: vm::VM,
+    load_error: Option<String>,
+    input: Box<dyn BufRead + 'a>,
+    output: Box<dyn Write + 'a>
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: Program) -> Self {
+        let mut machine = vm::VM::new();
+        // Programs are checked when they are made, run reports anything that still fails
+        let load_error = machine.load(program.obj.as_bytes()).err();
+        Vm {
+            machine,
+            load_error,
+            input: Box::new(BufReader::new(io::stdin())),
+            output: Box::new(io::stdout())
+        }
+    }
+
+    // read() takes lines from input and write() goes to output
+    pub fn with_io<R: BufRead + 'a, W: Write + 'a>(mut self, input: R, output: W) -> Self {
+        self.input = Box::new(input);
+        self.output = Box::new(output);
+        self
+    }
+
+    pub fn with_canvas<C: Canvas + 'static>(mut self, canvas: C) -> Self {
+        self.machine.set_canvas(Box::new(canvas));
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.machine.set_seed(seed);
+        self
+    }
+
+    // Canvas settings, they replace the ones recorded in the program
+    pub fn with_canvas_size(mut self, width: u32, height: u32) -> Self {
+        self.machine.set_canvas_size(width, height);
+        self
+    }
+
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.machine.set_title(title);
+        self
+    }
+
+    pub fn with_corner_origin(mut self, corner_origin: bool) -> Self {
+        self.machine.set_corner_origin(corner_origin);
+        self
+    }
+
+    // Returns the runtime error that stopped the program, if any
+    pub fn run(mut self) -> Result<(), String> {
+        if let Some(err) = self.load_error {
+            return Err(err);
+        }
+        self.machine.run(&mut *self.input, &mut *self.output)
+    }
+}
@@ -1,28 +1,87 @@
-#[macro_use]
-extern crate pest_derive;
-
 use env_logger::Env;
+use log::{error, info, warn};
 use std::env;
+use std::fs;
 use turtle;
 
-mod memory;
-mod compiler;
-
-mod vm;
+use me_myself::canvas::SvgCanvas;
+use me_myself::{compile_str_with, CompileOptions, Program, Vm};
 
 // Compile a program and set an output file
-fn compile(in_file: &str, out_file: &str) {
-    let mut compiler = compiler::MMCompiler::new(); // pass from file data structure
+fn compile(in_file: &str, out_file: &str, options: CompileOptions) {
+    let source = match fs::read_to_string(in_file) {
+        Ok(source) => source,
+        Err(err) => {
+            error!("Cannot read {}: {}", in_file, err);
+            std::process::exit(1);
+        }
+    };
+
+    let program = match compile_str_with(&source, options) {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            for warning in diagnostics.warnings.iter() {
+                warn!("{}", warning);
+            }
+            error!("{}", diagnostics);
+            std::process::exit(1);
+        }
+    };
+
+    for warning in program.warnings() {
+        warn!("{}", warning);
+    }
+
+    info!("Output file: {}", out_file);
+    if let Err(err) = fs::write(out_file, program.to_bytes()) {
+        error!("Cannot write {}: {}", out_file, err);
+        std::process::exit(1);
+    }
+}
 
-    compiler.process_file(in_file);   
-    compiler.write_obj_file(out_file).unwrap();
+// Options of the run command
+#[derive(Default)]
+struct RunOptions<'a> {
+    seed: Option<u64>,
+    headless: Option<&'a str>,
+    size: Option<(u32, u32)>,
+    title: Option<&'a str>,
+    corner_origin: Option<bool>
 }
 
 // Run a file
-fn run(file_name: &str) {
-    let mut machine = vm::VM::new();
-    machine.load_file(file_name).unwrap();
-    machine.run();
+fn run(file_name: &str, options: RunOptions) {
+    let program = match fs::read(file_name).map_err(|err| err.to_string()).and_then(|bytes| Program::from_bytes(&bytes)) {
+        Ok(program) => program,
+        Err(err) => {
+            error!("Cannot load {}: {}", file_name, err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut machine = Vm::new(program);
+    if let Some(seed) = options.seed {
+        machine = machine.with_seed(seed);
+    }
+    if let Some(out_dir) = options.headless {
+        machine = machine.with_canvas(SvgCanvas::new(out_dir));
+    }
+
+    // Flags replace the settings recorded in the obj file
+    if let Some((width, height)) = options.size {
+        machine = machine.with_canvas_size(width, height);
+    }
+    if let Some(title) = options.title {
+        machine = machine.with_title(title);
+    }
+    if let Some(corner_origin) = options.corner_origin {
+        machine = machine.with_corner_origin(corner_origin);
+    }
+
+    if let Err(err) = machine.run() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
 }
 
 fn main() {
@@ -50,7 +109,19 @@ USAGE:
 
 COMMAND:
     compile <in_file> <out_file>    Compile a me_myself program. If not given, <out_file> is "file.obj".
-    run <in_file>                   Run a .obj me_myself program. If not given, <in_file> is "file.obj".
+    run <in_file> [OPTIONS]         Run a .obj me_myself program. If not given, <in_file> is "file.obj".
+
+COMPILE OPTIONS:
+    --int64                         Use 64 bit ints instead of 32 bit ones.
+    --allow-shadowing               No warning when a local variable hides a global one.
+
+RUN OPTIONS:
+    --seed <n>                      Seed of the random number functions.
+    --headless <dir>                Draw without a window, each Frame() is saved in <dir> as
+                                    frame_0001.svg, frame_0002.svg, ... and the end as final.svg.
+    --size <w>x<h>                  Canvas size, replaces CanvasSize() of the program.
+    --title <text>                  Canvas title, replaces Title() of the program.
+    --coordinates <center|corner>   Where (0, 0) is, replaces Coordinates() of the program.
     help                            Show this message
 
 "#;
@@ -65,19 +136,91 @@ COMMAND:
     let first_arg = &args[1];
     match first_arg.as_str() {
         "compile" => {
-            if args.len() == 3 {
-                compile(&args[2], "file.obj");
-            } else if args.len() == 4 {
-                compile(&args[2], &args[3]);
+            let mut options: CompileOptions = Default::default();
+            let mut compile_args: Vec<&String> = Vec::new();
+            for arg in args.iter().skip(2) {
+                if arg == "--int64" {
+                    options.int64 = true;
+                } else if arg == "--allow-shadowing" {
+                    options.allow_shadowing = true;
+                } else {
+                    compile_args.push(arg);
+                }
+            }
+
+            if compile_args.len() == 1 {
+                compile(compile_args[0], "file.obj", options);
+            } else if compile_args.len() == 2 {
+                compile(compile_args[0], compile_args[1], options);
             } else {
                 println!("{}", help);
             }
         }
         "run" => {
-            if args.len() == 2 {
-                run("file.obj");
-            } else if args.len() == 3 {
-                run(&args[2]);
+            // Take out the flags, the rest are positional
+            let mut options: RunOptions = Default::default();
+            let mut run_args: Vec<&String> = Vec::new();
+            let mut arg_iter = args.iter().skip(2);
+            while let Some(arg) = arg_iter.next() {
+                if arg == "--seed" {
+                    match arg_iter.next().map(|n| n.parse::<u64>()) {
+                        Some(Ok(n)) => options.seed = Some(n),
+                        _ => {
+                            println!("--seed needs a positive integer");
+                            return;
+                        }
+                    }
+                } else if arg == "--headless" {
+                    match arg_iter.next() {
+                        Some(out_dir) => options.headless = Some(out_dir.as_str()),
+                        None => {
+                            println!("--headless needs an output directory");
+                            return;
+                        }
+                    }
+                } else if arg == "--size" {
+                    let size = arg_iter.next().and_then(|size| {
+                        let mut dims = size.split('x').map(|dim| dim.parse::<u32>());
+                        match (dims.next(), dims.next(), dims.next()) {
+                            (Some(Ok(width)), Some(Ok(height)), None) if width > 0 && height > 0 => Some((width, height)),
+                            _ => None
+                        }
+                    });
+                    match size {
+                        Some(size) => options.size = Some(size),
+                        None => {
+                            println!("--size needs a width and a height, like 640x480");
+                            return;
+                        }
+                    }
+                } else if arg == "--title" {
+                    match arg_iter.next() {
+                        Some(title) => options.title = Some(title.as_str()),
+                        None => {
+                            println!("--title needs a text");
+                            return;
+                        }
+                    }
+                } else if arg == "--coordinates" {
+                    match arg_iter.next().map(|mode| mode.as_str()) {
+                        Some("center") => options.corner_origin = Some(false),
+                        Some("corner") => options.corner_origin = Some(true),
+                        _ => {
+                            println!("--coordinates needs center or corner");
+                            return;
+                        }
+                    }
+                } else {
+                    run_args.push(arg);
+                }
+            }
+
+            if run_args.is_empty() {
+                run("file.obj", options);
+            } else if run_args.len() == 1 {
+                run(run_args[0], options);
+            } else {
+                println!("{}", help);
             }
         }
         "help" | &_ => {
@@ -29,12 +29,61 @@ pub enum BaseDirs {
     CteUpperLim = 34000
 }
 
+// Size of the ints of a program, 32 bits unless it is compiled with --int64
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntWidth {
+    Bits32,
+    Bits64
+}
+
+impl Default for IntWidth {
+    fn default() -> Self {
+        IntWidth::Bits32
+    }
+}
+
+impl IntWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            IntWidth::Bits32 => 32,
+            IntWidth::Bits64 => 64
+        }
+    }
+
+    pub fn from_bits(bits: u32) -> Option<IntWidth> {
+        match bits {
+            32 => Some(IntWidth::Bits32),
+            64 => Some(IntWidth::Bits64),
+            _ => None
+        }
+    }
+
+    // The value, if it fits in an int of this size
+    pub fn fit(self, val: i64) -> Option<i64> {
+        match self {
+            IntWidth::Bits32 if val < i32::MIN as i64 || val > i32::MAX as i64 => None,
+            _ => Some(val)
+        }
+    }
+
+    // A float without its decimals (toward zero), if it fits in an int of this size
+    pub fn from_float(self, val: f64) -> Option<i64> {
+        let truncated = val.trunc();
+        // 2^63 is the first float above i64::MAX, NaN fails both comparisons
+        let in_i64 = truncated >= i64::MIN as f64 && truncated < 9_223_372_036_854_775_808.0;
+        if in_i64 { self.fit(truncated as i64) } else { None }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum VarValue {
-    Int(i32),
+    Int(i64),
     Float(f64),
-    Char(String),
-    Bool(bool)
+    Char(char),
+    Bool(bool),
+    Str(String),
+    // A ref param, the call depth of the frame and the location of the variable
+    Ref(usize, i32)
 }
 
 use std::fmt;
@@ -45,7 +94,9 @@ impl fmt::Display for VarValue {
             VarValue::Int(val) => write!(f, "{}", val),
             VarValue::Float(val) => write!(f, "{}", val),
             VarValue::Char(val) => write!(f, "{}", val),
-            VarValue::Bool(val) => write!(f, "{}", val)
+            VarValue::Bool(val) => write!(f, "{}", val),
+            VarValue::Str(val) => write!(f, "{}", val),
+            VarValue::Ref(frame, location) => write!(f, "ref {} {}", frame, location)
         }
     }
 }   
@@ -67,7 +118,7 @@ impl Memory {
             self.variables.insert(i + BaseDirs::GlobalFloat as i32, VarValue::Float(0.0));
         }
         for i in 0..g_c {
-            self.variables.insert(i + BaseDirs::GlobalChar as i32, VarValue::Char(String::from("")));
+            self.variables.insert(i + BaseDirs::GlobalChar as i32, VarValue::Char('\0'));
         }
     }
 
@@ -80,7 +131,7 @@ impl Memory {
             self.variables.insert(i + BaseDirs::LocalFloat as i32, VarValue::Float(0.0));
         }
         for i in 0..locals.2 {
-            self.variables.insert(i + BaseDirs::LocalChar as i32, VarValue::Char(String::from("")));
+            self.variables.insert(i + BaseDirs::LocalChar as i32, VarValue::Char('\0'));
         }
 
         for i in 0..temp.0 {
@@ -90,7 +141,7 @@ impl Memory {
             self.variables.insert(i + BaseDirs::TempFloat as i32, VarValue::Float(0.0));
         }
         for i in 0..temp.2 {
-            self.variables.insert(i + BaseDirs::TempChar as i32, VarValue::Char(String::from("")));
+            self.variables.insert(i + BaseDirs::TempChar as i32, VarValue::Char('\0'));
         }
         for i in 0..temp.3 {
             self.variables.insert(i + BaseDirs::TempBool as i32, VarValue::Bool(false));
@@ -1,13 +1,14 @@
-use std::fs::File;
-use std::io::{self, prelude::*, BufReader};
+use std::io::prelude::*;
 use std::str::FromStr;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use turtle::Turtle;
+use crate::canvas::{Canvas, Pen, TurtleCanvas};
 
-use log::{debug, error, log_enabled, info, Level};
+use log::{debug, log_enabled, info, warn, Level};
 
-use crate::memory::{Memory, VarValue, BaseDirs};
+use crate::memory::{Memory, VarValue, BaseDirs, IntWidth};
+use crate::format::{unescape_char, unescape_string, FormatSpec};
 
 #[derive(Debug, Default)]
 struct Func {
@@ -32,9 +33,171 @@ struct Quadruple {
     out_op: OutOp
 }
 
+// Field of a line of the obj file, a missing field or a bad number is an error with the line
+fn obj_field<T: FromStr>(fields: &[&str], index: usize, line: &str) -> Result<T, String> {
+    fields.get(index).and_then(|field| field.parse().ok()).ok_or_else(|| format!("Bad line in program: {}", line))
+}
+
+// Draws an arc to the left as short segments, a negative radius turns right
+fn draw_arc(turtle: &mut dyn Pen, radius: f64, degrees: f64) {
+    let steps = (degrees.abs() / 5.0).ceil().max(1.0);
+    let step_angle = degrees / steps;
+    let step_len = 2.0 * std::f64::consts::PI * radius.abs() * step_angle.abs() / 360.0;
+    for _ in 0..steps as i32 {
+        if radius >= 0.0 {
+            turtle.left(step_angle / 2.0);
+            turtle.forward(step_len);
+            turtle.left(step_angle / 2.0);
+        } else {
+            turtle.right(step_angle / 2.0);
+            turtle.forward(step_len);
+            turtle.right(step_angle / 2.0);
+        }
+    }
+}
+
+// Reads the red, green and blue params of a color function, each one from 0 to 255
+fn color_param(new_mem: &Memory) -> Result<turtle::Color, String> {
+    let mut channels = [0.0; 3];
+    for (i, name) in ["red", "green", "blue"].iter().enumerate() {
+        match new_mem.get_val(BaseDirs::LocalFloat as i32 + i as i32).unwrap() {
+            VarValue::Float(value) if (0.0..=255.0).contains(&value) => channels[i] = value,
+            VarValue::Float(value) => return Err(format!("{} must be between 0 and 255. Got: {}", name, value)),
+            _ => unreachable!("RGB must be floats")
+        }
+    }
+    Ok(turtle::Color::rgb(channels[0], channels[1], channels[2]))
+}
+
+// SplitMix64 generator, the same seed always gives the same numbers
+#[derive(Debug, Default)]
+struct Rng {
+    state: u64
+}
+
+impl Rng {
+    fn seed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Float in [0, 1)
+    fn next_float(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Int in [lo, hi]
+    fn next_int(&mut self, lo: i64, hi: i64) -> Result<i64, String> {
+        if lo > hi {
+            return Err(format!("randomInt lower limit {} is greater than upper limit {}", lo, hi));
+        }
+        // Up to 2^64 values when the limits are the smallest and largest 64 bit ints
+        let range = (hi as i128 - lo as i128 + 1) as u128;
+        Ok((lo as i128 + (self.next_u64() as u128 % range) as i128) as i64)
+    }
+}
+
+// Runs a math built-in over its int or float arguments
+fn math_builtin(op: &str, args: &[VarValue], int_width: IntWidth) -> Result<VarValue, String> {
+    let mut nums: Vec<f64> = Vec::new();
+    let mut ints: Vec<i64> = Vec::new();
+    for arg in args {
+        match arg {
+            VarValue::Int(val) => {
+                nums.push(*val as f64);
+                ints.push(*val);
+            }
+            VarValue::Float(val) => nums.push(*val),
+            _ => return Err(format!("{} expects int or float arguments. Got: {:?}", op, arg))
+        }
+    }
+    let all_ints = ints.len() == args.len();
+    let overflow = |val: String| format!("{} does not fit in a {} bit int", val, int_width.bits());
+
+    let result = match (op, nums.as_slice()) {
+        ("Sin", [x]) => VarValue::Float(x.sin()),
+        ("Cos", [x]) => VarValue::Float(x.cos()),
+        ("Sqrt", [x]) => VarValue::Float(x.sqrt()),
+        ("Pow", [x, y]) => VarValue::Float(x.powf(*y)),
+        ("Atan2", [y, x]) => VarValue::Float(y.atan2(*x)),
+        ("Floor", [x]) => VarValue::Int(int_width.from_float(x.floor()).ok_or_else(|| overflow(format!("floor({})", x)))?),
+        ("Round", [x]) => VarValue::Int(int_width.from_float(x.round()).ok_or_else(|| overflow(format!("round({})", x)))?),
+        // Ints stay exact, the abs of the smallest int is one more than the largest
+        ("Abs", [x]) => if all_ints {
+            VarValue::Int(ints[0].checked_abs().and_then(|val| int_width.fit(val)).ok_or_else(|| overflow(format!("abs({})", ints[0])))?)
+        } else {
+            VarValue::Float(x.abs())
+        },
+        ("Min", [x, y]) => if all_ints { VarValue::Int(ints[0].min(ints[1])) } else { VarValue::Float(x.min(*y)) },
+        ("Max", [x, y]) => if all_ints { VarValue::Int(ints[0].max(ints[1])) } else { VarValue::Float(x.max(*y)) },
+        ("Pi", []) => VarValue::Float(std::f64::consts::PI),
+        _ => return Err(format!("Wrong arguments for {}: {:?}", op, args))
+    };
+    Ok(result)
+}
+
+// Char with the given code point
+fn int_to_char(code: i64) -> Result<char, String> {
+    if code < 0 || code > u32::MAX as i64 {
+        return Err(format!("{} is not a valid character", code));
+    }
+    char::from_u32(code as u32).ok_or_else(|| format!("{} is not a valid character", code))
+}
+
+// Runs int(x), float(x) or char(x). Floats go to int by dropping the decimals (toward zero),
+// chars go to numbers by their code point and numbers go to chars the same way
+fn cast_value(op: &str, value: &VarValue, int_width: IntWidth) -> Result<VarValue, String> {
+    let float_to_int = |val: f64| int_width.from_float(val).ok_or_else(|| format!("{} does not fit in a {} bit int", val, int_width.bits()));
+
+    let result = match (op, value) {
+        ("ToInt", VarValue::Int(val)) => VarValue::Int(*val),
+        ("ToInt", VarValue::Float(val)) => VarValue::Int(float_to_int(*val)?),
+        ("ToInt", VarValue::Char(val)) => VarValue::Int(*val as i64),
+        ("ToFloat", VarValue::Int(val)) => VarValue::Float(*val as f64),
+        ("ToFloat", VarValue::Float(val)) => VarValue::Float(*val),
+        ("ToFloat", VarValue::Char(val)) => VarValue::Float(*val as u32 as f64),
+        ("ToChar", VarValue::Int(val)) => VarValue::Char(int_to_char(*val)?),
+        ("ToChar", VarValue::Float(val)) => VarValue::Char(int_to_char(float_to_int(*val)?)?),
+        ("ToChar", VarValue::Char(val)) => VarValue::Char(*val),
+        _ => return Err(format!("Cannot convert {:?} with {}", value, op))
+    };
+    Ok(result)
+}
+
+// Checked int arithmetic, the result must fit in the int size of the program
+fn int_arithmetic(op: &str, val_l: i64, val_r: i64, int_width: IntWidth) -> Result<VarValue, String> {
+    let (symbol, result) = match op {
+        "Sum" => ("+", val_l.checked_add(val_r)),
+        "Sub" => ("-", val_l.checked_sub(val_r)),
+        "Mult" => ("*", val_l.checked_mul(val_r)),
+        "Div" => ("/", val_l.checked_div(val_r)),
+        "Mod" => ("%", val_l.checked_rem(val_r)),
+        _ => unreachable!()
+    };
+    if val_r == 0 && (op == "Div" || op == "Mod") {
+        return Err(format!("Runtime error: division by zero in {} {} {}", val_l, symbol, val_r));
+    }
+    match result.and_then(|val| int_width.fit(val)) {
+        Some(val) => Ok(VarValue::Int(val)),
+        None => Err(format!("Runtime error: {} {} {} overflows a {} bit int", val_l, symbol, val_r, int_width.bits()))
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct VM {
     prog_name: String,
+    // Canvas settings from the obj file or the command line
+    canvas_size: Option<(u32, u32)>,
+    canvas_title: Option<String>,
+    corner_origin: bool,
+    int_width: IntWidth,
     func_list: HashMap<String, Func>,
     curr_memory: Memory,
     global_memory: Memory,
@@ -42,6 +205,12 @@ pub struct VM {
     ip: usize,
     ip_stack: Vec<usize>,
     memory_stack: Vec<Memory>,
+    return_register: Option<VarValue>,
+    active_turtle: usize,
+    canvas: Option<Box<dyn Canvas>>,
+    rng: Rng,
+    // Values of the last input line that read() hasn't used
+    pending_input: VecDeque<String>,
     constants: HashMap<i32, VarValue>,
 }
 
@@ -63,183 +232,191 @@ impl VM {
         new_vm.func_list.insert("StartFill".to_string(), Default::default());
         new_vm.func_list.insert("EndFill".to_string(), Default::default());
         new_vm.func_list.insert("FillColor".to_string(), Func { locals: (0, 3, 0), ..Default::default() });
+        new_vm.func_list.insert("Circle".to_string(), Func { locals: (0, 1, 0), ..Default::default() });
+        new_vm.func_list.insert("Arc".to_string(), Func { locals: (0, 2, 0), ..Default::default() });
+        new_vm.func_list.insert("Dot".to_string(), Func { locals: (0, 1, 0), ..Default::default() });
+        new_vm.func_list.insert("SetHeading".to_string(), Func { locals: (0, 1, 0), ..Default::default() });
+        new_vm.func_list.insert("Speed".to_string(), Func { locals: (1, 0, 0), ..Default::default() });
+        new_vm.func_list.insert("Instant".to_string(), Default::default());
+        new_vm.func_list.insert("Frame".to_string(), Default::default());
+
+        // Programs without seed() or --seed get a different sequence on each run
+        let time_seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(0);
+        new_vm.rng.seed(time_seed);
 
         new_vm
     }
 
-    // read .obj file and create quadruples, globals, and function table
-    pub fn load_file(&mut self, file_name: &str) -> io::Result<()> {
-        let file = File::open(file_name)?;
-        let lines = BufReader::new(file).lines();
-
-        // Constants
-        for line in lines {
-            if let Ok(line) = line {
-                let mut info = line.split_whitespace();
-                match info.next().unwrap() {
-                    "P" => {
-                        let prog_name = info.next().unwrap();
-                        info!("Program {}", prog_name);
-                        self.prog_name = prog_name.to_string();
-                    }
-                    "C" => {
-                        let value = info.next().unwrap();
-                        let location: i32 = info.next().unwrap().parse().unwrap();
-                        let r#type = info.next().unwrap();
-                        let final_val = match r#type {
-                            "Int" => VarValue::Int(value.parse().unwrap()),
-                            "Float" => VarValue::Float(value.parse().unwrap()),
-                            "Char" => VarValue::Char(value.to_string()),
-                            &_ => { unreachable!()}
-                        };
-                        self.constants.insert(location, final_val);
-                    }
-                    "G" => {
-                        let globals: Vec<i32> = info.map(|s| i32::from_str(s).expect("Can't parse number")).collect();
-                        self.global_memory.set_globals(globals[0], globals[1], globals[2]);
-                    }
-                    "F" => {
-                        let func_name = info.next().unwrap();
-                        let func_start: usize = info.next().unwrap().parse().expect("Can't parse number");
-                        let func_params: Vec<i32> = info.map(|s| i32::from_str(s).expect("Can't parse number")).collect(); 
-                        
-                        let new_func = Func {
-                            locals: (func_params[0], func_params[1], func_params[2]),
-                            temps: (func_params[3], func_params[4], func_params[5], func_params[6]),
-                            start_loc: func_start
-                        };
+    // Seed the random number generator
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng.seed(seed);
+    }
 
-                        self.func_list.insert(func_name.to_string(), new_func);
-                    }
-                    "A" => {
-                        let op = info.next().unwrap();
-                        match op {
-                            // Actions that use number as out_op
-                            "Sum" | 
-                            "Sub" | 
-                            "Mult" | 
-                            "Div" | 
-                            "Mod" |
-                            "MoreThan" | 
-                            "LessThan" |
-                            "LessOrEqualThan" |
-                            "MoreOrEqualThan" |
-                            "Equal" |
-                            "NotEqual" |
-                            "And" |
-                            "Or" |
-                            "Assign" | 
-                            "Return" => {
-                                let params: Vec<i32> = info.map(|s| i32::from_str(s).unwrap()).collect();
-                                let lh_op = Some(params[0]);
-                                let rh_op = Some(params[1]);
-                                let out_op = OutOp::Mem(params[2] as i32);
-                                let new_quad = Quadruple {
-                                    op: op.to_string(),
-                                    lh_op: lh_op,
-                                    rh_op: rh_op,
-                                    out_op: out_op
-                                };
-                                self.quad_list.push(new_quad);
-                            }
-                            "GotoF" => {
-                                let params: Vec<i32> = info.map(|s| i32::from_str(s).unwrap()).collect();
-                                let lh_op = Some(params[0]);
-                                let out_op = OutOp::Pos(params[2] as usize);
-                                let new_quad = Quadruple {
-                                    op: op.to_string(),
-                                    lh_op: lh_op,
-                                    rh_op: None,
-                                    out_op: out_op
-                                };
-                                self.quad_list.push(new_quad);
-                            }
-                            "Goto" => {
-                                let params: Vec<i32> = info.map(|s| i32::from_str(s).unwrap()).collect();
-                                let out_op = OutOp::Pos(params[2] as usize);
-                                let new_quad = Quadruple {
-                                    op: op.to_string(),
-                                    lh_op: None,
-                                    rh_op: None,
-                                    out_op: out_op
-                                };
-                                self.quad_list.push(new_quad);
-                            }
-                            "Era" | "Gosub" => {
-                                let out_op = OutOp::Str(info.nth(2).unwrap().to_string());
-                                let new_quad = Quadruple {
-                                    op: op.to_string(),
-                                    lh_op: None,
-                                    rh_op: None,
-                                    out_op: out_op
-                                };
-                                self.quad_list.push(new_quad);
-                            }
-                            "Param" | "Read" => {
-                                let out_op = OutOp::Mem(info.nth(2).unwrap().parse::<i32>().unwrap());
-                                let new_quad = Quadruple {
-                                    op: op.to_string(),
-                                    lh_op: None,
-                                    rh_op: None,
-                                    out_op: out_op
-                                };
-                                self.quad_list.push(new_quad);
-                            }
-                            "Print" => {
-                                let info = info.skip(2);
-                                let print_vec: Vec<String> = info.map(|s| s.to_string()).collect();
-                                let print_out = print_vec.join(" ");
-                                if &print_out[0..1] == "\"" {
-                                    let new_quad = Quadruple {
-                                        op: op.to_string(),
-                                        lh_op: None,
-                                        rh_op: None,
-                                        out_op: OutOp::Str(print_out)
-                                    };
-                                    self.quad_list.push(new_quad);
-                                } else {
-                                    let new_quad = Quadruple {
-                                        op: op.to_string(),
-                                        lh_op: None,
-                                        rh_op: None,
-                                        out_op: OutOp::Mem(print_out.parse().unwrap())
-                                    };
-                                    self.quad_list.push(new_quad);
-                                }
-                            }
-                            "EndFunc" | 
-                            "EndFuncS" |
-                            "Forward" |
-                            "Backward" |
-                            "Left" |
-                            "Right" |
-                            "Center" |
-                            "PenUp" |
-                            "PenDown" |
-                            "Color" |
-                            "Size" |
-                            "Clear" |
-                            "Position" |
-                            "BackgroundColor" |
-                            "StartFill" |
-                            "EndFill" |
-                            "FillColor" => { // Special function because out is empty
-                                let new_quad = Quadruple {
-                                    op: op.to_string(),
-                                    lh_op: None,
-                                    rh_op: None,
-                                    out_op: OutOp::None
-                                };
-                                self.quad_list.push(new_quad);
-                            }
-                            &_ => {error!("Missing action {}", op); unimplemented!()}
+    // Canvas settings given on the command line, they replace the ones in the obj file
+    pub fn set_canvas_size(&mut self, width: u32, height: u32) {
+        self.canvas_size = Some((width, height));
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas_title = Some(title.to_string());
+    }
+
+    pub fn set_corner_origin(&mut self, corner_origin: bool) {
+        self.corner_origin = corner_origin;
+    }
+
+    // read .obj contents and create quadruples, globals, and function table
+    pub fn load<R: BufRead>(&mut self, reader: R) -> Result<(), String> {
+        for line in reader.lines() {
+            let line = line.map_err(|err| format!("Cannot read program: {}", err))?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let bad_line = || format!("Bad line in program: {}", line);
+            match fields.first() {
+                None => {}
+                Some(&"P") => {
+                    let prog_name = fields.get(1).ok_or_else(bad_line)?;
+                    info!("Program {}", prog_name);
+                    self.prog_name = prog_name.to_string();
+                }
+                Some(&"S") => {
+                    let setting = fields.get(1).ok_or_else(bad_line)?;
+                    match *setting {
+                        "size" => {
+                            let width = obj_field(&fields, 2, &line)?;
+                            let height = obj_field(&fields, 3, &line)?;
+                            self.canvas_size = Some((width, height));
                         }
+                        "coordinates" => self.corner_origin = fields.get(2) == Some(&"corner"),
+                        "ints" => self.int_width = IntWidth::from_bits(obj_field(&fields, 2, &line)?).ok_or_else(|| String::from("Ints must be 32 or 64 bits"))?,
+                        "title" => self.canvas_title = Some(unescape_string(line.splitn(3, ' ').nth(2).ok_or_else(bad_line)?)?),
+                        &_ => warn!("Unknown canvas setting {}", setting)
                     }
-                    &_ => { error!("Missing {}", info.nth(0).unwrap()); }
                 }
+                Some(&"C") => {
+                    // C value location type, a string value is quoted and can have spaces
+                    let mut cte_fields = line[1..].trim_start().rsplitn(3, ' ');
+                    let r#type = cte_fields.next().ok_or_else(bad_line)?;
+                    let location: i32 = cte_fields.next().and_then(|location| location.parse().ok()).ok_or_else(bad_line)?;
+                    let value = cte_fields.next().ok_or_else(bad_line)?;
+                    let final_val = match r#type {
+                        "Int" => VarValue::Int(value.parse().map_err(|_| bad_line())?),
+                        "Float" => VarValue::Float(value.parse().map_err(|_| bad_line())?),
+                        "Char" => VarValue::Char(unescape_char(value)?),
+                        "String" => VarValue::Str(unescape_string(value)?),
+                        &_ => return Err(bad_line())
+                    };
+                    self.constants.insert(location, final_val);
+                }
+                Some(&"G") => {
+                    self.global_memory.set_globals(obj_field(&fields, 1, &line)?, obj_field(&fields, 2, &line)?, obj_field(&fields, 3, &line)?);
+                }
+                Some(&"F") => {
+                    let func_name = fields.get(1).ok_or_else(bad_line)?;
+                    let new_func = Func {
+                        locals: (obj_field(&fields, 3, &line)?, obj_field(&fields, 4, &line)?, obj_field(&fields, 5, &line)?),
+                        temps: (obj_field(&fields, 6, &line)?, obj_field(&fields, 7, &line)?, obj_field(&fields, 8, &line)?, obj_field(&fields, 9, &line)?),
+                        start_loc: obj_field(&fields, 2, &line)?
+                    };
+
+                    self.func_list.insert(func_name.to_string(), new_func);
+                }
+                Some(&"A") => {
+                    // A op lh rh out, -1 when an operand is not used
+                    let op = fields.get(1).ok_or_else(bad_line)?;
+                    let (lh_op, rh_op, out_op) = match *op {
+                        // Actions that use number as out_op
+                        "Sum" |
+                        "Sub" |
+                        "Mult" |
+                        "Div" |
+                        "Mod" |
+                        "MoreThan" |
+                        "LessThan" |
+                        "LessOrEqualThan" |
+                        "MoreOrEqualThan" |
+                        "Equal" |
+                        "NotEqual" |
+                        "And" |
+                        "Or" |
+                        "Assign" |
+                        "Sin" |
+                        "Cos" |
+                        "Sqrt" |
+                        "Pow" |
+                        "Abs" |
+                        "Floor" |
+                        "Round" |
+                        "Atan2" |
+                        "Min" |
+                        "Max" |
+                        "Pi" |
+                        "Random" |
+                        "RandomInt" |
+                        "GetX" |
+                        "GetY" |
+                        "Heading" |
+                        "IsPenDown" |
+                        "DistanceTo" |
+                        "NewTurtle" |
+                        "Ord" |
+                        "Chr" |
+                        "ToInt" |
+                        "ToFloat" |
+                        "ToChar" => (Some(obj_field(&fields, 2, &line)?), Some(obj_field(&fields, 3, &line)?), OutOp::Mem(obj_field(&fields, 4, &line)?)),
+                        "Return" | "Seed" | "UseTurtle" => (Some(obj_field(&fields, 2, &line)?), None, OutOp::None),
+                        "GotoF" | "GotoTable" => (Some(obj_field(&fields, 2, &line)?), None, OutOp::Pos(obj_field(&fields, 4, &line)?)),
+                        "Goto" => (None, None, OutOp::Pos(obj_field(&fields, 4, &line)?)),
+                        "Era" | "Gosub" => (None, None, OutOp::Str(fields.get(4).ok_or_else(bad_line)?.to_string())),
+                        "Param" | "ParamRef" | "Read" | "GetReturn" | "Print" | "WriteText" => (None, None, OutOp::Mem(obj_field(&fields, 4, &line)?)),
+                        "PrintFmt" => (Some(obj_field(&fields, 2, &line)?), None, OutOp::Str(fields.get(4).ok_or_else(bad_line)?.to_string())),
+                        "Error" => (None, None, OutOp::Str(fields.get(4..).unwrap_or_default().join(" "))),
+                        "EndFunc" |
+                        "EndFuncS" |
+                        "PrintSpace" |
+                        "PrintLn" |
+                        "Forward" |
+                        "Backward" |
+                        "Left" |
+                        "Right" |
+                        "Center" |
+                        "PenUp" |
+                        "PenDown" |
+                        "Color" |
+                        "Size" |
+                        "Clear" |
+                        "Position" |
+                        "BackgroundColor" |
+                        "StartFill" |
+                        "EndFill" |
+                        "Circle" |
+                        "Arc" |
+                        "Dot" |
+                        "SetHeading" |
+                        "Speed" |
+                        "Instant" |
+                        "Frame" |
+                        "FillColor" => (None, None, OutOp::None), // Special function because out is empty
+                        &_ => return Err(format!("Unknown action {} in program", op))
+                    };
+                    self.quad_list.push(Quadruple { op: op.to_string(), lh_op, rh_op, out_op });
+                }
+                Some(_) => return Err(bad_line())
             }
         }
-        // debug!("{:?}", self);
+
+        // Every jump must land on a quadruple and every call on a function of the program
+        for quad in self.quad_list.iter() {
+            match &quad.out_op {
+                OutOp::Pos(pos) if *pos >= self.quad_list.len() => return Err(format!("{} jumps outside of the program", quad.op)),
+                OutOp::Str(func_name) if (quad.op == "Era" || quad.op == "Gosub") && !self.func_list.contains_key(func_name) => {
+                    return Err(format!("{} of unknown function {}", quad.op, func_name))
+                }
+                _ => {}
+            }
+        }
+        if !self.func_list.contains_key("main") {
+            return Err(String::from("Program has no main function"));
+        }
 
         Ok(())
     }
@@ -248,7 +425,11 @@ impl VM {
         if location >= BaseDirs::GlobalInt as i32 && location < BaseDirs::GlobalUpperLim as i32 {
             self.global_memory.get_val(location)
         } else if location >= BaseDirs::LocalInt as i32 && location < BaseDirs::TempUpperLim as i32 {
-            self.curr_memory.get_val(location)
+            match self.curr_memory.get_val(location)? {
+                // Ref params read the variable of the caller
+                VarValue::Ref(frame, target) => self.frame_memory(frame, target).get_val(target),
+                val => Ok(val)
+            }
         } else if location >= BaseDirs::CteInt as i32 && location < BaseDirs::CteUpperLim as i32 {
             match self.constants.get(&location) {
                 Some(val) => Ok(val.clone()),
@@ -264,12 +445,47 @@ impl VM {
             debug!("Setting {} to {:?}", location, new_val);
             self.global_memory.set_val(location, new_val).unwrap()
         } else if location >= BaseDirs::LocalInt as i32 && location < BaseDirs::TempUpperLim as i32 {
-            self.curr_memory.set_val(location, new_val).unwrap()
+            match self.curr_memory.get_val(location)? {
+                // Ref params write the variable of the caller
+                VarValue::Ref(frame, target) => self.frame_memory_mut(frame, target).set_val(target, new_val)?,
+                _ => self.curr_memory.set_val(location, new_val).unwrap()
+            }
         } else {
             return Err(format!("Memory location {} not initialized", location))
         };
         Ok(())
     }
+
+    // Memory of the call at the given depth, 0 is main, global variables are in the same memory for every call
+    fn frame_memory(&self, frame: usize, location: i32) -> &Memory {
+        if location < BaseDirs::LocalInt as i32 {
+            &self.global_memory
+        } else if frame == self.memory_stack.len() {
+            &self.curr_memory
+        } else {
+            &self.memory_stack[frame]
+        }
+    }
+
+    fn frame_memory_mut(&mut self, frame: usize, location: i32) -> &mut Memory {
+        if location < BaseDirs::LocalInt as i32 {
+            &mut self.global_memory
+        } else if frame == self.memory_stack.len() {
+            &mut self.curr_memory
+        } else {
+            &mut self.memory_stack[frame]
+        }
+    }
+
+    // Ref to a variable of the current call. A ref param gives what it already points to
+    fn reference(&self, location: i32) -> VarValue {
+        if location >= BaseDirs::LocalInt as i32 && location < BaseDirs::LocalUpperLim as i32 {
+            if let Ok(VarValue::Ref(frame, target)) = self.curr_memory.get_val(location) {
+                return VarValue::Ref(frame, target);
+            }
+        }
+        VarValue::Ref(self.memory_stack.len(), location)
+    }
     
     // Returns the type of value that is saved at a certain memory location
     fn get_mem_type(&self, location: i32) -> VarValue {
@@ -284,29 +500,61 @@ impl VM {
         location >= BaseDirs::CteFloat as i32 && location < BaseDirs::CteChar as i32 - 1 {
             VarValue::Float(0.0)
         } else {
-            VarValue::Char("".to_string())
+            VarValue::Char('\0')
         }
     }
     
-    pub fn run(&mut self) {
-        let mut turtle = Turtle::new();
-        turtle.drawing_mut().set_title(self.prog_name.as_str());
-        // turtle.drawing_mut().set_size([400, 400]);
+    // Next whitespace separated value of the input, read(a, b) can take both from the same line
+    fn next_input(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<String, String> {
+        while self.pending_input.is_empty() {
+            output.flush().map_err(|err| format!("Cannot write output: {}", err))?;
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) => return Err(String::from("Runtime error: read() reached the end of the input")),
+                Ok(_) => self.pending_input.extend(line.split_whitespace().map(|value| value.to_string())),
+                Err(err) => return Err(format!("Cannot read input: {}", err))
+            }
+        }
+        Ok(self.pending_input.pop_front().unwrap())
+    }
+
+    // Runs the program, write() goes to output and read() takes lines from input
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), String> {
+        // Without a headless canvas the turtles draw on a window
+        let mut canvas = self.canvas.take().unwrap_or_else(|| Box::new(TurtleCanvas::new()));
+        canvas.set_title(self.canvas_title.as_deref().unwrap_or(self.prog_name.as_str()));
+        // Same default size as the turtle window
+        let (width, height) = self.canvas_size.unwrap_or((800, 600));
+        if self.canvas_size.is_some() {
+            canvas.set_size(width, height);
+        }
+        // With corner coordinates (0, 0) is the bottom left corner
+        if self.corner_origin {
+            canvas.set_center([width as f64 / 2.0, height as f64 / 2.0].into());
+        }
         let mut new_mem: Memory = Default::default();
         let mut param_pos: (i32, i32, i32) = (0,0,0);
+        // Calls that are still receiving params when another call starts (f(g(x)))
+        let mut era_stack: Vec<(Memory, (i32, i32, i32))> = Vec::new();
 
         // initialize main memory
 
-        let func_data = self.func_list.get("main").unwrap();
+        let func_data = self.func_list.get("main").ok_or_else(|| String::from("Program has no main function"))?;
         self.curr_memory.set_new_func(func_data.locals, func_data.temps);
         new_mem.set_new_func(func_data.locals, func_data.temps);
 
         debug!("Global mem: {:?}", self.global_memory);
 
         loop {
-            let curr_quad: &Quadruple = self.quad_list.get(self.ip).unwrap();
+            let curr_quad: &Quadruple = match self.quad_list.get(self.ip) {
+                Some(quad) => quad,
+                None => return Err(String::from("Runtime error: program ended without EndFunc"))
+            };
             debug!("Current quad {}: {:?}", self.ip, curr_quad);
             // println!("Current quad {}: {:?}", self.ip, curr_quad);
+
+            // UseTurtle only lasts for the quadruple after it
+            let turtle = canvas.turtle(std::mem::take(&mut self.active_turtle));
             match curr_quad.op.as_str() {
                 "Goto" => {
                     if let OutOp::Pos(next_pos) = curr_quad.out_op {
@@ -333,11 +581,28 @@ impl VM {
                         unreachable!();
                     }
                 }
-                "Param" => {
+                "GotoTable" => {
+                    // Jump to the table entry at the given offset
+                    let lh = curr_quad.lh_op.unwrap();
+                    let lh_mem: VarValue = self.get_val(lh).unwrap();
+                    match (lh_mem, &curr_quad.out_op) {
+                        (VarValue::Int(offset), OutOp::Pos(table_start)) => {
+                            self.ip = table_start + offset as usize;
+                        }
+                        _ => unreachable!()
+                    }
+                }
+                "Param" | "ParamRef" => {
                     if let OutOp::Mem(param) = &curr_quad.out_op {
-                        let param_val: VarValue = self.get_val(*param).unwrap();
+                        // A ref param takes the slot of the type of its variable
+                        let (param_val, slot_type) = if curr_quad.op == "ParamRef" {
+                            (self.reference(*param), self.get_mem_type(*param))
+                        } else {
+                            let param_val: VarValue = self.get_val(*param).unwrap();
+                            (param_val.clone(), param_val)
+                        };
                         // debug!("Param init, mem: {:?}, {:?}", new_mem, self.global_memory);
-                        match param_val {
+                        match slot_type {
                             VarValue::Int(_) => {
                                 new_mem.set_val(BaseDirs::LocalInt as i32 + param_pos.0, param_val).unwrap();
                                 param_pos.0 += 1;
@@ -359,7 +624,8 @@ impl VM {
                 }
                 "Era" => {
                     if let OutOp::Str(func_name) = &curr_quad.out_op {
-                        new_mem.clear();
+                        era_stack.push((std::mem::take(&mut new_mem), param_pos));
+                        param_pos = (0,0,0);
                         let func_data = self.func_list.get(func_name).unwrap();
                         new_mem.set_new_func(func_data.locals, func_data.temps);
                         self.ip += 1;
@@ -368,28 +634,42 @@ impl VM {
                     }
                 }
                 "EndFuncS" => {
-                    param_pos = (0,0,0);
+                    let (last_mem, last_pos) = era_stack.pop().unwrap();
+                    new_mem = last_mem;
+                    param_pos = last_pos;
                     self.ip += 1;
                 }
                 "Gosub" => {
-                    self.memory_stack.push(self.curr_memory.clone());
+                    let (last_mem, last_pos) = era_stack.pop().unwrap();
+                    let func_mem = std::mem::replace(&mut new_mem, last_mem);
+                    param_pos = last_pos;
+                    self.memory_stack.push(std::mem::replace(&mut self.curr_memory, func_mem));
 
-                    self.curr_memory = new_mem.clone();
                     if let OutOp::Str(func_name) = &curr_quad.out_op {
                         let func: &Func = self.func_list.get(func_name).unwrap();
                         self.ip_stack.push(self.ip.clone());
                         self.ip = func.start_loc;
-                        param_pos = (0,0,0);
+                        self.return_register = None;
 
                     } else {
                         unreachable!()
                     }
                 }
                 "Return" => {
+                    // Value stays in the return register until the caller reads it
                     let lh = curr_quad.lh_op.unwrap();
                     let lh_mem: VarValue = self.get_val(lh).unwrap();
+                    self.return_register = Some(lh_mem);
+                    self.ip += 1;
+                }
+                "GetReturn" => {
                     if let OutOp::Mem(out_mem) = curr_quad.out_op {
-                        self.set_val(out_mem, lh_mem).unwrap();
+                        match self.return_register.take() {
+                            Some(ret_val) => self.set_val(out_mem, ret_val).unwrap(),
+                            None => {
+                                return Err(String::from("Runtime error: function ended without returning a value"));
+                            }
+                        }
                         self.ip += 1;
                     } else {
                         unreachable!()
@@ -402,7 +682,7 @@ impl VM {
                     let rh_mem = self.get_val(rh).unwrap();
                     if let OutOp::Mem(out_mem) = curr_quad.out_op {
                         let out_val = match (lh_mem, rh_mem) {
-                            (VarValue::Int(val_l), VarValue::Int(val_r)) => VarValue::Int(val_l + val_r),
+                            (VarValue::Int(val_l), VarValue::Int(val_r)) => int_arithmetic("Sum", val_l, val_r, self.int_width)?,
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Float(val_l + val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l as f64 + val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l + val_r),
@@ -421,7 +701,7 @@ impl VM {
                     let rh_mem = self.get_val(rh).unwrap();
                     if let OutOp::Mem(out_mem) = curr_quad.out_op {
                         let out_val = match (lh_mem, rh_mem) {
-                            (VarValue::Int(val_l), VarValue::Int(val_r)) => VarValue::Int(val_l - val_r),
+                            (VarValue::Int(val_l), VarValue::Int(val_r)) => int_arithmetic("Sub", val_l, val_r, self.int_width)?,
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Float(val_l - val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l as f64 - val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l - val_r),
@@ -440,7 +720,7 @@ impl VM {
                     let rh_mem = self.get_val(rh).unwrap();
                     if let OutOp::Mem(out_mem) = curr_quad.out_op {
                         let out_val = match (lh_mem, rh_mem) {
-                            (VarValue::Int(val_l), VarValue::Int(val_r)) => VarValue::Int(val_l * val_r),
+                            (VarValue::Int(val_l), VarValue::Int(val_r)) => int_arithmetic("Mult", val_l, val_r, self.int_width)?,
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Float(val_l * val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l as f64 * val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l * val_r),
@@ -459,7 +739,7 @@ impl VM {
                     let rh_mem = self.get_val(rh).unwrap();
                     if let OutOp::Mem(out_mem) = curr_quad.out_op {
                         let out_val = match (lh_mem, rh_mem) {
-                            (VarValue::Int(val_l), VarValue::Int(val_r)) => VarValue::Int(val_l / val_r),
+                            (VarValue::Int(val_l), VarValue::Int(val_r)) => int_arithmetic("Div", val_l, val_r, self.int_width)?,
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Float(val_l / val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l as f64 / val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l / val_r),
@@ -478,7 +758,7 @@ impl VM {
                     let rh_mem = self.get_val(rh).unwrap();
                     if let OutOp::Mem(out_mem) = curr_quad.out_op {
                         let out_val = match (lh_mem, rh_mem) {
-                            (VarValue::Int(val_l), VarValue::Int(val_r)) => VarValue::Int(val_l % val_r),
+                            (VarValue::Int(val_l), VarValue::Int(val_r)) => int_arithmetic("Mod", val_l, val_r, self.int_width)?,
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Float(val_l % val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l as f64 % val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Float(val_l % val_r),
@@ -501,6 +781,7 @@ impl VM {
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Bool(val_l > val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Bool(val_l as f64 > val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Bool(val_l > val_r),
+                            (VarValue::Char(val_l), VarValue::Char(val_r)) => VarValue::Bool(val_l > val_r),
                             _ => unreachable!()
                         };
                         self.set_val(out_mem, out_val).unwrap();
@@ -521,6 +802,7 @@ impl VM {
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Bool(val_l < val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Bool((val_l as f64) < val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Bool(val_l < val_r),
+                            (VarValue::Char(val_l), VarValue::Char(val_r)) => VarValue::Bool(val_l < val_r),
                             _ => unreachable!()
                         };
                         self.set_val(out_mem, out_val).unwrap();
@@ -580,6 +862,7 @@ impl VM {
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Bool(val_l >= val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Bool((val_l as f64) >= val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Bool(val_l >= val_r),
+                            (VarValue::Char(val_l), VarValue::Char(val_r)) => VarValue::Bool(val_l >= val_r),
                             _ => unreachable!()
                         };
                         self.set_val(out_mem, out_val).unwrap();
@@ -599,6 +882,7 @@ impl VM {
                             (VarValue::Float(val_l), VarValue::Int(val_r)) => VarValue::Bool(val_l <= val_r as f64),
                             (VarValue::Int(val_l), VarValue::Float(val_r)) => VarValue::Bool((val_l as f64) <= val_r),
                             (VarValue::Float(val_l), VarValue::Float(val_r)) => VarValue::Bool(val_l <= val_r),
+                            (VarValue::Char(val_l), VarValue::Char(val_r)) => VarValue::Bool(val_l <= val_r),
                             _ => unreachable!()
                         };
                         self.set_val(out_mem, out_val).unwrap();
@@ -639,49 +923,191 @@ impl VM {
                         unreachable!()
                     }
                 }
+                "Sin" | "Cos" | "Sqrt" | "Pow" | "Abs" | "Floor" | "Round" | "Atan2" | "Min" | "Max" | "Pi" => {
+                    // Unused operands are -1
+                    let mut args: Vec<VarValue> = Vec::new();
+                    for loc in [curr_quad.lh_op, curr_quad.rh_op].iter().flatten().filter(|loc| **loc >= 0) {
+                        args.push(self.get_val(*loc).unwrap());
+                    }
+                    if let OutOp::Mem(out_mem) = curr_quad.out_op {
+                        match math_builtin(curr_quad.op.as_str(), &args, self.int_width) {
+                            Ok(out_val) => self.set_val(out_mem, out_val).unwrap(),
+                            Err(message) => {
+                                return Err(format!("Runtime error: {}", message));
+                            }
+                        }
+                        self.ip += 1;
+                    } else {
+                        unreachable!()
+                    }
+                }
+                "Random" => {
+                    if let OutOp::Mem(out_mem) = curr_quad.out_op {
+                        let out_val = VarValue::Float(self.rng.next_float());
+                        self.set_val(out_mem, out_val).unwrap();
+                        self.ip += 1;
+                    } else {
+                        unreachable!()
+                    }
+                }
+                "RandomInt" => {
+                    let lo = self.get_val(curr_quad.lh_op.unwrap()).unwrap();
+                    let hi = self.get_val(curr_quad.rh_op.unwrap()).unwrap();
+                    if let (OutOp::Mem(out_mem), VarValue::Int(lo), VarValue::Int(hi)) = (&curr_quad.out_op, lo, hi) {
+                        match self.rng.next_int(lo, hi) {
+                            Ok(val) => self.set_val(*out_mem, VarValue::Int(val)).unwrap(),
+                            Err(message) => {
+                                return Err(format!("Runtime error: {}", message));
+                            }
+                        }
+                        self.ip += 1;
+                    } else {
+                        unreachable!("randomInt limits must be ints")
+                    }
+                }
+                "ToInt" | "ToFloat" | "ToChar" => {
+                    let value = self.get_val(curr_quad.lh_op.unwrap()).unwrap();
+                    if let OutOp::Mem(out_mem) = curr_quad.out_op {
+                        match cast_value(curr_quad.op.as_str(), &value, self.int_width) {
+                            Ok(out_val) => self.set_val(out_mem, out_val).unwrap(),
+                            Err(message) => {
+                                return Err(format!("Runtime error: {}", message));
+                            }
+                        }
+                        self.ip += 1;
+                    } else {
+                        unreachable!()
+                    }
+                }
+                "Ord" => {
+                    if let (OutOp::Mem(out_mem), VarValue::Char(c)) = (&curr_quad.out_op, self.get_val(curr_quad.lh_op.unwrap()).unwrap()) {
+                        self.set_val(*out_mem, VarValue::Int(c as i64)).unwrap();
+                        self.ip += 1;
+                    } else {
+                        unreachable!("ord takes a char")
+                    }
+                }
+                "Chr" => {
+                    if let (OutOp::Mem(out_mem), VarValue::Int(code)) = (&curr_quad.out_op, self.get_val(curr_quad.lh_op.unwrap()).unwrap()) {
+                        match int_to_char(code) {
+                            Ok(c) => self.set_val(*out_mem, VarValue::Char(c)).unwrap(),
+                            Err(_) => {
+                                return Err(format!("Runtime error: chr({}) is not a valid character", code));
+                            }
+                        }
+                        self.ip += 1;
+                    } else {
+                        unreachable!("chr takes an int")
+                    }
+                }
+                "Seed" => {
+                    if let VarValue::Int(seed) = self.get_val(curr_quad.lh_op.unwrap()).unwrap() {
+                        self.rng.seed(seed as u64);
+                        self.ip += 1;
+                    } else {
+                        unreachable!("Seed must be int")
+                    }
+                }
+                "NewTurtle" => {
+                    if let OutOp::Mem(out_mem) = curr_quad.out_op {
+                        let turtle_id = canvas.add_turtle();
+                        self.set_val(out_mem, VarValue::Int(turtle_id as i64)).unwrap();
+                        self.ip += 1;
+                    } else {
+                        unreachable!()
+                    }
+                }
+                "UseTurtle" => {
+                    match self.get_val(curr_quad.lh_op.unwrap()).unwrap() {
+                        VarValue::Int(turtle_id) if turtle_id >= 0 && (turtle_id as usize) < canvas.turtle_count() => {
+                            self.active_turtle = turtle_id as usize;
+                            self.ip += 1;
+                        }
+                        VarValue::Int(turtle_id) => {
+                            return Err(format!("Runtime error: Turtle {} does not exist, NewTurtle() has created {}", turtle_id, canvas.turtle_count() - 1));
+                        }
+                        _ => unreachable!("Turtle handle must be int")
+                    }
+                }
+                "GetX" | "GetY" | "Heading" | "IsPenDown" | "DistanceTo" => {
+                    let out_val = match curr_quad.op.as_str() {
+                        "GetX" => VarValue::Float(turtle.position().x),
+                        "GetY" => VarValue::Float(turtle.position().y),
+                        "Heading" => VarValue::Float(turtle.heading()),
+                        "IsPenDown" => VarValue::Bool(turtle.is_pen_down()),
+                        _ => {
+                            let x = self.get_val(curr_quad.lh_op.unwrap()).unwrap();
+                            let y = self.get_val(curr_quad.rh_op.unwrap()).unwrap();
+                            let position = turtle.position();
+                            match (x, y) {
+                                (VarValue::Float(x), VarValue::Float(y)) => VarValue::Float((x - position.x).hypot(y - position.y)),
+                                (VarValue::Int(x), VarValue::Float(y)) => VarValue::Float((x as f64 - position.x).hypot(y - position.y)),
+                                (VarValue::Float(x), VarValue::Int(y)) => VarValue::Float((x - position.x).hypot(y as f64 - position.y)),
+                                (VarValue::Int(x), VarValue::Int(y)) => VarValue::Float((x as f64 - position.x).hypot(y as f64 - position.y)),
+                                _ => unreachable!("DistanceTo point must be numeric")
+                            }
+                        }
+                    };
+                    if let OutOp::Mem(out_mem) = curr_quad.out_op {
+                        self.set_val(out_mem, out_val).unwrap();
+                        self.ip += 1;
+                    } else {
+                        unreachable!()
+                    }
+                }
                 "Print" => {
                     if let OutOp::Mem(mem_loc) = curr_quad.out_op {
                         let mem_data = self.get_val(mem_loc).unwrap();
-                        println!("{}", mem_data);
-                    } else if let OutOp::Str(letrero) = &curr_quad.out_op {
-                        println!("{}", letrero);
+                        write!(output, "{}", mem_data).map_err(|err| format!("Cannot write output: {}", err))?;
                     } else {
                         unreachable!();
                     }
                     self.ip += 1;
                 }
+                "PrintFmt" => {
+                    if let OutOp::Str(spec) = &curr_quad.out_op {
+                        let value = self.get_val(curr_quad.lh_op.unwrap()).unwrap();
+                        let format_spec = FormatSpec::parse(spec).map_err(|err| format!("Runtime error: {}", err))?;
+                        write!(output, "{}", format_spec.format(&value)).map_err(|err| format!("Cannot write output: {}", err))?;
+                        self.ip += 1;
+                    } else {
+                        unreachable!();
+                    }
+                }
+                "PrintSpace" => {
+                    write!(output, " ").map_err(|err| format!("Cannot write output: {}", err))?;
+                    self.ip += 1;
+                }
+                "PrintLn" => {
+                    writeln!(output).map_err(|err| format!("Cannot write output: {}", err))?;
+                    self.ip += 1;
+                }
                 "Read" => {
                     if let OutOp::Mem(mem_loc) = curr_quad.out_op {
-                        let mut input = String::new();
-                        match io::stdin().read_line(&mut input) {
-                            Ok(n) => {
-                                match self.get_mem_type(mem_loc) {
-                                    VarValue::Int(_) => { 
-                                        match input.trim().parse::<i32>() {
-                                            Ok(val) => { self.set_val(mem_loc, VarValue::Int(val)).unwrap() },
-                                            Err(err) => panic!("Value cannot be parsed into int {}", input)
-                                        }
-                                    }
-                                    VarValue::Float(_) => { 
-                                        match input.trim().parse::<f64>() {
-                                            Ok(val) => { self.set_val(mem_loc, VarValue::Float(val)).unwrap() },
-                                            Err(err) => panic!("Value cannot be parsed into float {}.", input)
-                                        }
-                                        
-                                    }
-                                    VarValue::Char(_) => {
-                                        if input.trim().len() == 1 {
-                                            self.set_val(mem_loc, VarValue::Char(input)).unwrap();
-                                        } else {
-                                            panic!("Char must be single character");
-                                        }
-                                    }
-                                    _ => { unreachable!() }
+                        let value = self.next_input(input, output)?;
+                        let parsed = match self.get_mem_type(mem_loc) {
+                            VarValue::Int(_) => value.parse::<i64>().ok().and_then(|val| self.int_width.fit(val)).map(VarValue::Int).ok_or("an int"),
+                            VarValue::Float(_) => value.parse::<f64>().map(VarValue::Float).map_err(|_| "a float"),
+                            VarValue::Char(_) => {
+                                let mut chars = value.chars();
+                                match (chars.next(), chars.next()) {
+                                    (Some(c), None) => Ok(VarValue::Char(c)),
+                                    _ => Err("a single character")
                                 }
                             }
-                            Err(error) => println!("error: {}", error),
+                            _ => unreachable!()
+                        };
+                        match parsed {
+                            Ok(val) => {
+                                self.set_val(mem_loc, val).unwrap();
+                                self.ip += 1;
+                            }
+                            // Ask again for the same variable, the rest of the line is dropped
+                            Err(expected) => {
+                                self.pending_input.clear();
+                                writeln!(output, "\"{}\" is not {}, try again:", value, expected).map_err(|err| format!("Cannot write output: {}", err))?;
+                            }
                         }
-                        self.ip += 1;
                     } else {
                         unreachable!()
                     }
@@ -690,16 +1116,14 @@ impl VM {
                     let lh = curr_quad.lh_op.unwrap();
                     let lh_mem: VarValue = self.get_val(lh).unwrap();
                     if let OutOp::Mem(out_mem) = curr_quad.out_op {
+                        // The compiler converts the value first, so both sides have the same type
                         match (self.get_mem_type(out_mem), lh_mem.clone()) {
-                            (VarValue::Int(_), VarValue::Int(_)) => self.set_val(out_mem, lh_mem).unwrap(),
-                            (VarValue::Int(_), VarValue::Float(val)) => self.set_val(out_mem, VarValue::Int(val as i32)).unwrap(),
-                            (VarValue::Float(_), VarValue::Int(val)) => self.set_val(out_mem, VarValue::Float(val as f64)).unwrap(),
-                            (VarValue::Float(_), VarValue::Float(_)) => self.set_val(out_mem, lh_mem).unwrap(),
-                            (VarValue::Char(_), VarValue::Int(val)) => self.set_val(out_mem, VarValue::Char(val.to_string())).unwrap(),
-                            (VarValue::Char(_), VarValue::Float(val)) => self.set_val(out_mem, VarValue::Char(val.to_string())).unwrap(),
+                            (VarValue::Int(_), VarValue::Int(_)) |
+                            (VarValue::Float(_), VarValue::Float(_)) |
                             (VarValue::Char(_), VarValue::Char(_)) => self.set_val(out_mem, lh_mem).unwrap(),
-                            _=> unimplemented!("WHAT DID YOU DO?!?!")
-
+                            (out_type, _) => {
+                                return Err(format!("Runtime error: cannot assign {:?} to {:?} at {}, recompile the program", lh_mem, out_type, out_mem));
+                            }
                         }
                         self.ip += 1;
                     } else {
@@ -776,50 +1200,118 @@ impl VM {
                     let y = new_mem.get_val(BaseDirs::LocalFloat as i32 + 1).unwrap();
                     match (x, y) {
                         (VarValue::Float(x), VarValue::Float(y)) => {
-                            turtle.go_to([x, y]);
+                            turtle.go_to([x, y].into());
                             self.ip += 1;
                         }
                         _=> { unreachable!("RGB must be floats")}
                     }
                 }
-                "Color" => {
-                    let red = new_mem.get_val(BaseDirs::LocalFloat as i32).unwrap();
-                    let green = new_mem.get_val(BaseDirs::LocalFloat as i32 + 1).unwrap();
-                    let blue = new_mem.get_val(BaseDirs::LocalFloat as i32 + 2).unwrap();
-                    match (red, blue, green) {
-                        (VarValue::Float(red), VarValue::Float(blue), VarValue::Float(green)) => {
-                            turtle.set_pen_color(turtle::Color::rgb(red, green, blue));
-                            self.ip += 1;
+                "Color" | "BackgroundColor" | "FillColor" => {
+                    // The three functions take red, green, blue in the same order
+                    let color = match color_param(&new_mem) {
+                        Ok(color) => color,
+                        Err(err) => {
+                            return Err(format!("Runtime error: {} in {}", err, curr_quad.op));
                         }
-                        _=> { unreachable!("RGB must be floats")}
+                    };
+                    debug!("Setting {} {:?}", curr_quad.op, color);
+                    match curr_quad.op.as_str() {
+                        "Color" => turtle.set_pen_color(color),
+                        "FillColor" => turtle.set_fill_color(color),
+                        _ => canvas.set_background_color(color)
                     }
+                    self.ip += 1;
                 }
-                "BackgroundColor" => {
-                    let red = new_mem.get_val(BaseDirs::LocalFloat as i32).unwrap();
-                    let green = new_mem.get_val(BaseDirs::LocalFloat as i32 + 1).unwrap();
-                    let blue = new_mem.get_val(BaseDirs::LocalFloat as i32 + 2).unwrap();
-                    match (red, blue, green) {
-                        (VarValue::Float(red), VarValue::Float(blue), VarValue::Float(green)) => {
-                            turtle.drawing_mut().set_background_color(turtle::Color::rgb(red, green, blue));
+                "Circle" => {
+                    if let VarValue::Float(radius) = new_mem.get_val(BaseDirs::LocalFloat as i32).unwrap() {
+                        debug!("Param circle {}", radius);
+                        draw_arc(turtle, radius, 360.0);
+                        self.ip += 1;
+                    } else {
+                        unreachable!("Value must be float")
+                    }
+                }
+                "Arc" => {
+                    let radius = new_mem.get_val(BaseDirs::LocalFloat as i32).unwrap();
+                    let degrees = new_mem.get_val(BaseDirs::LocalFloat as i32 + 1).unwrap();
+                    match (radius, degrees) {
+                        (VarValue::Float(radius), VarValue::Float(degrees)) => {
+                            debug!("Param arc {} {}", radius, degrees);
+                            draw_arc(turtle, radius, degrees);
                             self.ip += 1;
                         }
-                        _=> { unreachable!("RGB must be floats")}
+                        _=> { unreachable!("Radius and degrees must be floats")}
                     }
                 }
-                "FillColor" => {
-                    let red = new_mem.get_val(BaseDirs::LocalFloat as i32).unwrap();
-                    let blue = new_mem.get_val(BaseDirs::LocalFloat as i32 + 1).unwrap();
-                    let green = new_mem.get_val(BaseDirs::LocalFloat as i32 + 2).unwrap();
-
-                    debug!("Setting fill color {:?} {:?} {:?}", red, blue, green);
-                    match (red, blue, green) {
-                        (VarValue::Float(red), VarValue::Float(blue), VarValue::Float(green)) => {
-                            turtle.set_fill_color(turtle::Color::rgb(red, green, blue));
-                            self.ip += 1;
+                "Dot" => {
+                    if let VarValue::Float(size) = new_mem.get_val(BaseDirs::LocalFloat as i32).unwrap() {
+                        debug!("Param dot {}", size);
+                        // Filled circle around the turtle with the pen color, the turtle ends where it started
+                        let position = turtle.position();
+                        let heading = turtle.heading();
+                        let pen_down = turtle.is_pen_down();
+                        let fill_color = turtle.fill_color();
+                        turtle.pen_up();
+                        turtle.go_to([position.x, position.y - size / 2.0].into());
+                        turtle.set_heading(0.0);
+                        turtle.set_fill_color(turtle.pen_color());
+                        turtle.begin_fill();
+                        draw_arc(turtle, size / 2.0, 360.0);
+                        turtle.end_fill();
+                        turtle.set_fill_color(fill_color);
+                        turtle.go_to(position);
+                        turtle.set_heading(heading);
+                        if pen_down {
+                            turtle.pen_down();
                         }
-                        _=> { unreachable!("RGB must be floats")}
+                        self.ip += 1;
+                    } else {
+                        unreachable!("Value must be float")
                     }
                 }
+                "SetHeading" => {
+                    if let VarValue::Float(angle) = new_mem.get_val(BaseDirs::LocalFloat as i32).unwrap() {
+                        debug!("Param set heading {}", angle);
+                        turtle.set_heading(angle);
+                        self.ip += 1;
+                    } else {
+                        unreachable!("Value must be float")
+                    }
+                }
+                "Speed" => {
+                    if let VarValue::Int(speed) = new_mem.get_val(BaseDirs::LocalInt as i32).unwrap() {
+                        debug!("Param speed {}", speed);
+                        // 0 draws instantly, 1 to 25 goes from slowest to fastest
+                        match speed {
+                            0..=25 => turtle.set_speed(speed as i32),
+                            _ => {
+                                return Err(format!("Runtime error: Speed must be between 0 and 25. Got: {}", speed));
+                            }
+                        }
+                        self.ip += 1;
+                    } else {
+                        unreachable!("Value must be int")
+                    }
+                }
+                "WriteText" => {
+                    if let OutOp::Mem(label_mem) = curr_quad.out_op {
+                        let label = self.get_val(label_mem).unwrap();
+                        turtle.write_text(&label.to_string());
+                        self.ip += 1;
+                    } else {
+                        unreachable!()
+                    }
+                }
+                "Instant" => {
+                    turtle.set_speed(0);
+                    self.ip += 1;
+                }
+                "Frame" => {
+                    if let Err(err) = canvas.frame() {
+                        return Err(format!("Runtime error: {}", err));
+                    }
+                    self.ip += 1;
+                }
                 "StartFill" => {
                     turtle.begin_fill();
                     self.ip += 1;
@@ -828,6 +1320,13 @@ impl VM {
                     turtle.end_fill();
                     self.ip += 1;
                 }
+                "Error" => {
+                    if let OutOp::Str(message) = &curr_quad.out_op {
+                        return Err(format!("Runtime error: {}", message));
+                    } else {
+                        unreachable!()
+                    }
+                }
                 "EndFunc" => {
                     if self.ip_stack.is_empty() {
                         break;
@@ -843,12 +1342,16 @@ impl VM {
                         debug!("after mem {:?}", self.curr_memory);
                     }
                 }
-                &_ => {
-                    error!("Unknown action: {}", curr_quad.op.as_str());
-                    unimplemented!("Missing action");
-                }
+                &_ => return Err(format!("Runtime error: Unknown action {}", curr_quad.op))
             }
         }
         debug!("{:?}", self.curr_memory);
+        output.flush().map_err(|err| format!("Cannot write output: {}", err))?;
+        canvas.finish()
+    }
+
+    // Draw somewhere else than the turtle window, like the headless svg canvas
+    pub fn set_canvas(&mut self, canvas: Box<dyn Canvas>) {
+        self.canvas = Some(canvas);
     }
 }
\ No newline at end of file
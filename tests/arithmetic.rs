@@ -0,0 +1,66 @@
+mod common;
+
+use common::{program, run_program_with};
+use me_myself::{compile_str_with, CompileOptions};
+
+// Int a and b for a main module
+const INTS: &str = "Program Ints;
+var int: a, b;
+";
+
+// Runs body in main with 32 or 64 bit ints, returns what it wrote
+fn run_ints(body: &str, int64: bool) -> Result<String, String> {
+    run_program_with(&program(INTS, "", body), "", CompileOptions { int64, ..Default::default() })
+}
+
+#[test]
+fn overflow_is_a_runtime_error_with_the_operation() {
+    let cases = [
+        ("a = 2147483647; b = a + 1;", "2147483647 + 1 overflows a 32 bit int"),
+        ("a = -2147483647; b = a - 2;", "-2147483647 - 2 overflows a 32 bit int"),
+        ("a = 65536; b = a * a;", "65536 * 65536 overflows a 32 bit int"),
+        ("a = -2147483647 - 1; b = a / -1;", "-2147483648 / -1 overflows a 32 bit int"),
+        ("a = -2147483647 - 1; b = abs(a);", "abs(-2147483648) does not fit in a 32 bit int")
+    ];
+    for (body, expected) in cases.iter() {
+        let err = run_ints(body, false).unwrap_err();
+        assert!(err.contains(expected), "{}: {}", body, err);
+    }
+}
+
+#[test]
+fn division_by_zero_is_a_runtime_error() {
+    let err = run_ints("a = 7; b = 0; write(a / b);", false).unwrap_err();
+    assert!(err.contains("division by zero in 7 / 0"), "{}", err);
+    let err = run_ints("a = 7; b = 0; write(a % b);", false).unwrap_err();
+    assert!(err.contains("division by zero in 7 % 0"), "{}", err);
+}
+
+#[test]
+fn int64_programs_keep_big_results() {
+    let output = run_ints("a = 2147483647; b = a + 1; write(b, a * a, 9223372036854775807);", true).unwrap();
+    assert_eq!(output, "2147483648 4611686014132420609 9223372036854775807\n");
+    let err = run_ints("a = 9223372036854775807; b = a + 1;", true).unwrap_err();
+    assert!(err.contains("overflows a 64 bit int"), "{}", err);
+}
+
+#[test]
+fn int_literals_must_fit_in_the_int_size() {
+    let err = run_ints("a = 3000000000;", false).unwrap_err();
+    assert!(err.contains("--int64"), "{}", err);
+    assert!(run_ints("a = 3000000000;", true).is_ok());
+}
+
+#[test]
+fn program_records_its_int_size() {
+    let source = "Program Ints;
+void module main() {
+    {
+        write(1);
+    }
+}
+";
+    let program = compile_str_with(source, CompileOptions { int64: true, ..Default::default() }).unwrap();
+    let obj = String::from_utf8(program.to_bytes()).unwrap();
+    assert!(obj.lines().any(|line| line == "S ints 64"), "{}", obj);
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use common::{final_svg, program};
+use me_myself::compile_str;
+
+// Calls the color functions and fills a triangle
+fn single_color_program(call: &str) -> String {
+    program("Program Single;\n", "", &format!("        {}\n        StartFill();\n        Forward(10.0);\n        Left(90.0);\n        Forward(10.0);\n        EndFill();", call))
+}
+
+#[test]
+fn color_functions_take_red_green_blue() {
+    let svg = final_svg(include_str!("../examples/colors.txt")).unwrap();
+    // Color and FillColor with numbers
+    assert!(svg.contains("stroke=\"rgba(255,0,0,1)\""));
+    assert!(svg.contains("fill=\"rgba(0,255,0,1)\""));
+    // Named colors
+    assert!(svg.contains("stroke=\"rgba(0,0,255,1)\""));
+    assert!(svg.contains("fill=\"rgba(255,165,0,1)\""));
+    // Hex colors, long and short
+    assert!(svg.contains("stroke=\"rgba(0,255,128,1)\""));
+    assert!(svg.contains("fill=\"rgba(255,0,136,1)\""));
+    assert!(svg.contains("fill=\"rgba(240,240,240,1)\""));
+}
+
+#[test]
+fn each_channel_maps_to_its_argument() {
+    let svg = final_svg(&single_color_program("Color(1.0, 2.0, 3.0); FillColor(4.0, 5.0, 6.0); BackgroundColor(7.0, 8.0, 9.0);")).unwrap();
+    assert!(svg.contains("stroke=\"rgba(1,2,3,1)\""));
+    assert!(svg.contains("<polygon") && svg.contains("fill=\"rgba(4,5,6,1)\""));
+    assert!(svg.contains("<rect") && svg.contains("fill=\"rgba(7,8,9,1)\""));
+}
+
+#[test]
+fn channel_out_of_range_is_a_runtime_error() {
+    let err = final_svg(&single_color_program("FillColor(0.0, 256.0, 0.0);")).unwrap_err();
+    assert!(err.contains("green"));
+}
+
+#[test]
+fn unknown_color_name_does_not_compile() {
+    assert!(compile_str(&single_color_program("Color(\"reddish\");")).is_err());
+    assert!(compile_str(&single_color_program("Color(\"#12345\");")).is_err());
+}
+
+#[test]
+fn fill_of_the_square_examples_matches_the_pen() {
+    // FillColor used to take blue before green, the squares now fill with the color of their lines
+    for source in [include_str!("../examples/squares2.txt"), include_str!("../examples/squaresFor.txt")].iter() {
+        let svg = final_svg(source).unwrap();
+        let polygon = svg.lines().find(|line| line.starts_with("<polygon")).unwrap();
+        let line = svg.lines().find(|line| line.starts_with("<line")).unwrap();
+        let fill = polygon.split("fill=\"").nth(1).unwrap().split('"').next().unwrap();
+        assert!(line.contains(&format!("stroke=\"{}\"", fill)), "{}\n{}", polygon, line);
+    }
+}
+
+#[test]
+fn background_of_the_snowflake_example_is_light_blue() {
+    let svg = final_svg(include_str!("../examples/snowflake.txt")).unwrap();
+    assert!(svg.contains("<rect") && svg.contains("fill=\"rgba(41,182,246,1)\""));
+}
@@ -0,0 +1,55 @@
+// Helpers shared by the integration tests, every test file uses only some of them
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use me_myself::canvas::SvgCanvas;
+use me_myself::{compile_str_with, CompileOptions, Vm};
+
+// Every run draws in its own folder so tests running at the same time don't share frames
+fn frames_dir() -> PathBuf {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let run = RUNS.fetch_add(1, Ordering::SeqCst);
+    std::env::temp_dir().join(format!("me_myself_test_{}_{}", std::process::id(), run))
+}
+
+fn run_headless(source: &str, input: &str, options: CompileOptions, frames: &PathBuf) -> Result<String, String> {
+    let program = compile_str_with(source, options).map_err(|diagnostics| diagnostics.to_string())?;
+    let mut output: Vec<u8> = Vec::new();
+    Vm::new(program)
+        .with_io(input.as_bytes(), &mut output)
+        .with_canvas(SvgCanvas::new(frames.to_str().unwrap()))
+        .run()?;
+    Ok(String::from_utf8(output).unwrap())
+}
+
+// Runs a program without a window with input as stdin, returns what it wrote
+pub fn run_program(source: &str, input: &str) -> Result<String, String> {
+    run_program_with(source, input, CompileOptions::default())
+}
+
+pub fn run_program_with(source: &str, input: &str, options: CompileOptions) -> Result<String, String> {
+    run_headless(source, input, options, &frames_dir())
+}
+
+// Runs a program without a window and returns the svg of its final drawing
+pub fn final_svg(source: &str) -> Result<String, String> {
+    let frames = frames_dir();
+    run_headless(source, "", CompileOptions::default(), &frames)?;
+    Ok(fs::read_to_string(frames.join("final.svg")).unwrap())
+}
+
+// The declarations of a program, a main module with locals and the given body.
+// declarations start with the Program line, locals is a var line without var, like "int: total;"
+pub fn program(declarations: &str, locals: &str, body: &str) -> String {
+    let locals = if locals.is_empty() { String::new() } else { format!("    var {}\n", locals) };
+    format!("{}
+void module main() {{
+{}    {{
+{}
+    }}
+}}
+", declarations, locals, body)
+}
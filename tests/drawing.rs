@@ -0,0 +1,10 @@
+mod common;
+
+use common::{final_svg, program};
+
+#[test]
+fn write_text_draws_at_the_turtle_position() {
+    let source = program("Program Text;\n", "", "        PenUp();\n        Color(\"red\");\n        Position(10.0, 20.0);\n        WriteText(\"a < b & \\\"c\\\"\");");
+    let svg = final_svg(&source).unwrap();
+    assert!(svg.contains("<text x=\"10.00\" y=\"-20.00\" fill=\"rgba(255,0,0,1)\" font-family=\"sans-serif\">a &lt; b &amp; &quot;c&quot;</text>"), "{}", svg);
+}
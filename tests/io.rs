@@ -0,0 +1,153 @@
+mod common;
+
+use common::run_program;
+use me_myself::{compile_str, Program};
+
+#[test]
+fn factorial_iterative_reads_scripted_input() {
+    let output = run_program(include_str!("../examples/factorial.txt"), "1\n5\n").unwrap();
+    assert_eq!(output.lines().last(), Some("120"));
+}
+
+#[test]
+fn factorial_recursive_reads_scripted_input() {
+    let output = run_program(include_str!("../examples/factorial.txt"), "2\n5\n").unwrap();
+    assert_eq!(output.lines().last(), Some("120"));
+}
+
+// Reads an int, a float and a char and writes them back
+const READ_VALUES: &str = "Program ReadValues;
+var int: a; float: b; char: c;
+
+void module main() {
+    {
+        read(a, b, c);
+        write(a);
+        write(b);
+        write(c);
+    }
+}
+";
+
+#[test]
+fn read_takes_several_values_from_one_line() {
+    let output = run_program(READ_VALUES, "  7 2.5   x \n").unwrap();
+    assert_eq!(output.lines().collect::<Vec<&str>>(), vec!["7", "2.5", "x"]);
+}
+
+#[test]
+fn read_takes_values_from_several_lines() {
+    let output = run_program(READ_VALUES, "7\n\n2.5\nx\n").unwrap();
+    assert_eq!(output.lines().collect::<Vec<&str>>(), vec!["7", "2.5", "x"]);
+}
+
+#[test]
+fn read_asks_again_on_bad_values() {
+    let output = run_program(READ_VALUES, "seven 1\n7 2.5 xyz 1.0\nx\n").unwrap();
+    assert_eq!(output.lines().collect::<Vec<&str>>(), vec![
+        "\"seven\" is not an int, try again:",
+        "\"xyz\" is not a single character, try again:",
+        "7",
+        "2.5",
+        "x"
+    ]);
+}
+
+#[test]
+fn read_at_end_of_input_is_an_error() {
+    let err = run_program(READ_VALUES, "7 2.5\n").unwrap_err();
+    assert!(err.contains("end of the input"));
+}
+
+#[test]
+fn write_puts_its_values_in_one_line() {
+    let source = "Program WriteLine;
+void module main() {
+    var int: a; float: b;
+    {
+        a = 3;
+        b = 0.5;
+        write(a, b, a * 2);
+        writeNoLn(a);
+        writeNoLn(b);
+        write(a + 1);
+    }
+}
+";
+    let output = run_program(source, "").unwrap();
+    assert_eq!(output, "3 0.5 6\n30.54\n");
+}
+
+#[test]
+fn format_applies_width_and_precision() {
+    let source = "Program Formats;
+void module main() {
+    var int: a; float: b;
+    {
+        a = 42;
+        b = 3.14159;
+        write(format(\"x = {} y = {:.2}\", a, b));
+        write(format(\"[{:5}] [{:<5}] [{:05}] [{:8.3}] [{:.1}]\", a, a, a, b, a));
+        write(format(\"{{}} {:>6.1}|\", 0.0 - b), a);
+    }
+}
+";
+    let output = run_program(source, "").unwrap();
+    assert_eq!(output.lines().collect::<Vec<&str>>(), vec![
+        "x = 42 y = 3.14",
+        "[   42] [42   ] [00042] [   3.142] [42.0]",
+        "{}   -3.1| 42"
+    ]);
+}
+
+#[test]
+fn format_needs_a_value_per_placeholder() {
+    let source = "Program Formats;
+void module main() {
+    {
+        write(format(\"{} {}\", 1));
+    }
+}
+";
+    assert!(compile_str(source).is_err());
+}
+
+#[test]
+fn strings_keep_spaces_and_escapes() {
+    let source = "Program Strings;
+void module main() {
+    {
+        write(\"a  b   c\");
+        write(\"tab\\there\", \"quote \\\"q\\\"\");
+        writeNoLn(\"two\\nlines\\\\\");
+        write(format(\"  {}  \", 1));
+    }
+}
+";
+    let output = run_program(source, "").unwrap();
+    assert_eq!(output, "a  b   c\ntab\there quote \"q\"\ntwo\nlines\\  1  \n");
+}
+
+#[test]
+fn unknown_escape_does_not_compile() {
+    let source = "Program Strings;
+void module main() {
+    {
+        write(\"bad \\q\");
+    }
+}
+";
+    assert!(compile_str(source).is_err());
+}
+
+#[test]
+fn broken_program_bytes_are_an_error() {
+    let bytes = compile_str(include_str!("../examples/factorial.txt")).unwrap().to_bytes();
+    assert!(Program::from_bytes(&bytes).is_ok());
+    // Cut in the middle of a line
+    let cut = String::from_utf8(bytes.clone()).unwrap().rfind("A Goto").unwrap() + 8;
+    assert!(Program::from_bytes(&bytes[..cut]).is_err());
+    // Unknown action and no main module
+    assert!(Program::from_bytes(b"P Broken\nA Jump -1 -1 -1\n").unwrap_err().contains("Jump"));
+    assert!(Program::from_bytes(b"P Broken\nG 0 0 0\n").unwrap_err().contains("main"));
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use common::{program, run_program};
+use me_myself::compile_str;
+
+// Modules with ref params for a main module
+const REFS: &str = "Program Refs;
+struct Point { float x; float y; }
+var int: total; float: f;
+
+void module swap(ref int a, ref int b) {
+    var int: tmp;
+    {
+        tmp = a;
+        a = b;
+        b = tmp;
+    }
+}
+
+void module addOne(ref int n) {
+    {
+        n = n + 1;
+    }
+}
+
+void module countDown(int level, ref int count) {
+    %% Passes its ref on, every level adds one to the same variable %%
+    {
+        if (level > 0) then {
+            addOne(count);
+            countDown(level - 1, count);
+        }
+    }
+}
+
+void module moveBy(ref Point p, float dx) {
+    {
+        p.x = p.x + dx;
+        p.y = p.y + dx;
+    }
+}
+
+void module readInto(ref float value) {
+    {
+        read(value);
+    }
+}
+
+void module copy(int n) {
+    {
+        n = 100;
+    }
+}
+";
+
+// Locals of main that the tests pass by ref
+const LOCALS: &str = "int: a, b; Point: p;";
+
+#[test]
+fn swap_changes_the_variables_of_the_caller() {
+    let output = run_program(&program(REFS, LOCALS, "a = 1; b = 2; swap(a, b); write(a, b);"), "").unwrap();
+    assert_eq!(output, "2 1\n");
+}
+
+#[test]
+fn refs_reach_globals_fields_and_other_calls() {
+    let source = program(REFS, LOCALS, "
+        total = 5;
+        countDown(3, total);
+        a = 0;
+        countDown(4, a);
+        p.x = 1;
+        p.y = 2;
+        moveBy(p, 0.5);
+        swap(total, a);
+        write(total, a, p.x, p.y);
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "4 8 1.5 2.5\n");
+}
+
+#[test]
+fn read_writes_through_a_ref() {
+    let output = run_program(&program(REFS, LOCALS, "readInto(f); write(f);"), "2.5\n").unwrap();
+    assert_eq!(output, "2.5\n");
+}
+
+#[test]
+fn ref_in_parentheses_is_still_the_variable() {
+    let output = run_program(&program(REFS, LOCALS, "a = 1; b = 2; swap((a), ((b))); write(a, b);"), "").unwrap();
+    assert_eq!(output, "2 1\n");
+}
+
+#[test]
+fn value_params_are_still_copies() {
+    let output = run_program(&program(REFS, LOCALS, "a = 1; copy(a); write(a);"), "").unwrap();
+    assert_eq!(output, "1\n");
+}
+
+#[test]
+fn refs_need_a_variable_of_the_same_type() {
+    let cases = [
+        ("swap(a + 1, b);", "Parameter 0 in call of swap is ref and needs a variable. Got: a + 1"),
+        ("swap(a, 2);", "Parameter 1 in call of swap is ref and needs a variable. Got: 2"),
+        ("swap(a, (b) * 1);", "Parameter 1 in call of swap is ref and needs a variable. Got: (b) * 1"),
+        ("swap(a, c);", "Variable c not declared yet."),
+        ("readInto(a);", "Parameter 0 in call of readInto is ref Float and needs a variable of that type. Got: Int")
+    ];
+    for (body, expected) in cases.iter() {
+        let err = compile_str(&program(REFS, LOCALS, body)).unwrap_err().to_string();
+        assert!(err.contains(expected), "{}: {}", body, err);
+    }
+}
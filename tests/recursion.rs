@@ -0,0 +1,74 @@
+mod common;
+
+use common::{program, run_program};
+
+// Mutual, deep and nested recursion, every result comes back through the return register
+const RECURSIVE: &str = "Program Recursive;
+var int: n;
+
+int module isEven(int n) {
+    {
+        if (n == 0) then {
+            return(1);
+        }
+        return(isOdd(n - 1));
+    }
+}
+
+int module isOdd(int n) {
+    {
+        if (n == 0) then {
+            return(0);
+        }
+        return(isEven(n - 1));
+    }
+}
+
+int module sumTo(int n) {
+    {
+        if (n == 0) then {
+            return(0);
+        }
+        return(n + sumTo(n - 1));
+    }
+}
+
+int module fib(int n) {
+    {
+        if (n < 2) then {
+            return(n);
+        }
+        return(fib(n - 1) + fib(n - 2));
+    }
+}
+";
+
+#[test]
+fn recursion_example_output() {
+    let output = run_program(include_str!("../examples/recursion.txt"), "").unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines, vec!["1 1 0", "2001000", "9", "36"]);
+}
+
+#[test]
+fn deep_recursion_keeps_every_frame() {
+    let output = run_program(&program(RECURSIVE, "", "        write(sumTo(20000));"), "").unwrap();
+    assert_eq!(output, "200010000\n");
+}
+
+#[test]
+fn mutual_recursion_alternates_modules() {
+    let output = run_program(&program(RECURSIVE, "", "        write(isEven(5001), isOdd(5001), isEven(4000));"), "").unwrap();
+    assert_eq!(output, "0 1 1\n");
+}
+
+#[test]
+fn results_of_nested_calls_do_not_clobber_each_other() {
+    let source = program(RECURSIVE, "", "
+        n = fib(fib(6)) + sumTo(fib(5));
+        write(n, fib(15), isEven(fib(6)));
+    ");
+    let output = run_program(&source, "").unwrap();
+    // fib(8) + sumTo(5)
+    assert_eq!(output, "36 610 1\n");
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use common::{program, run_program};
+use me_myself::{compile_str, compile_str_with, CompileOptions};
+
+// A global n and p and a module that hides n, main has a local total
+const SCOPES: &str = "Program Scopes;
+struct Point { float x; float y; }
+var int: n, i; Point: p;
+
+int module twice(int n) {
+    {
+        return (n * 2);
+    }
+}
+";
+
+// Int, float and char slots of a module in the obj
+fn local_slots(obj: &str, module: &str) -> Vec<i32> {
+    let line = obj.lines().find(|line| line.starts_with(&format!("F {} ", module))).unwrap();
+    line.split_whitespace().skip(3).take(3).map(|count| count.parse().unwrap()).collect()
+}
+
+#[test]
+fn block_variables_live_until_the_block_ends() {
+    let source = program(SCOPES, "int: total;", "
+        for (i = 1) to (3) do {
+            var int: square; float: half;
+            square = i * i;
+            half = i / 2.0;
+            total = total + square;
+            writeNoLn(square, half);
+        }
+        if (total > 10) then {
+            var char: c;
+            c = 'y';
+            write(c);
+        } else {
+            var char: c;
+            c = 'n';
+            write(c);
+        }
+        write(total);
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "1 0.54 19 1.5y\n14\n");
+}
+
+#[test]
+fn block_variables_start_at_zero_every_time() {
+    let source = program(SCOPES, "int: total;", "
+        i = 0;
+        while (i < 3) do {
+            var int: count; char: c;
+            count = count + 1;
+            writeNoLn(count, ord(c));
+            i = i + 1;
+        }
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "1 01 01 0");
+}
+
+#[test]
+fn sibling_blocks_reuse_their_slots() {
+    let source = program(SCOPES, "int: total;", "
+        if (n == 0) then {
+            var int: a, b; float: f;
+            a = 1;
+        }
+        if (n == 0) then {
+            var int: c; float: g, h;
+            if (n == 0) then {
+                var int: d;
+                d = 2;
+            }
+        }
+    ");
+    let obj = String::from_utf8(compile_str(&source).unwrap().to_bytes()).unwrap();
+    // total plus the largest blocks open at once
+    assert_eq!(local_slots(&obj, "main"), vec![3, 2, 0]);
+}
+
+#[test]
+fn block_names_are_checked() {
+    let cases = [
+        ("if (n == 0) then { var int: a; } write(a);", "Variable a not declared yet."),
+        ("if (n == 0) then { var int: total; }", "Variable total has already been declared in module main."),
+        ("if (n == 0) then { var int: a; float: a; }", "Variable a has already been declared")
+    ];
+    for (body, expected) in cases.iter() {
+        let err = compile_str(&program(SCOPES, "int: total;", body)).unwrap_err().to_string();
+        assert!(err.contains(expected), "{}: {}", body, err);
+    }
+}
+
+#[test]
+fn hiding_a_global_is_a_warning_that_can_be_turned_off() {
+    let source = program(SCOPES, "int: total;", "
+        n = 4;
+        while (n > 3) do {
+            var Point: p; float: i;
+            p.x = twice(n);
+            n = n - 1;
+            write(p.x);
+        }
+    ");
+    let program = compile_str(&source).unwrap();
+    assert_eq!(program.warnings(), [
+        "Line 5: n in module twice hides the global variable n",
+        "Line 17: p in module main hides the global variable p",
+        "Line 17: i in module main hides the global variable i"
+    ]);
+
+    let options = CompileOptions { allow_shadowing: true, ..Default::default() };
+    assert!(compile_str_with(&source, options).unwrap().warnings().is_empty());
+}
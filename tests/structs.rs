@@ -0,0 +1,117 @@
+mod common;
+
+use common::{program, run_program};
+use me_myself::compile_str;
+
+// Point and Segment structs, a global segment and modules that take them by value
+const SHAPES: &str = "Program Shapes;
+struct Point { float x; float y; }
+struct Segment { Point from; Point to; int id; }
+var Segment: seg;
+
+float module length(Segment s) {
+    {
+        return (sqrt(pow(s.to.x - s.from.x, 2) + pow(s.to.y - s.from.y, 2)));
+    }
+}
+
+void module moveAway(Point p) {
+    {
+        p.x = p.x + 100;
+        write(p.x);
+    }
+}
+";
+
+#[test]
+fn struct_example_draws_two_stars() {
+    let output = run_program(include_str!("../examples/structs.txt"), "").unwrap();
+    assert_eq!(output, "-150 50 0\n");
+}
+
+#[test]
+fn fields_are_read_and_written_like_variables() {
+    let source = program(SHAPES, "Point: p;", "
+        read(seg.from.x, seg.from.y, seg.id);
+        seg.to.x = seg.from.x + 3;
+        seg.to.y = seg.from.y + 4;
+        write(seg.id, length(seg));
+    ");
+    let output = run_program(&source, "1 2 7\n").unwrap();
+    assert_eq!(output, "7 5\n");
+}
+
+#[test]
+fn structs_are_copied_by_value() {
+    let source = program(SHAPES, "Point: p;", "
+        p.x = 1;
+        p.y = 2;
+        seg.from = p;
+        p.x = 10;
+        moveAway(p);
+        write(p.x, seg.from.x, seg.from.y);
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "110\n10 1 2\n");
+}
+
+#[test]
+fn struct_in_parentheses_is_still_the_variable() {
+    let source = program(SHAPES, "Point: p;", "
+        p.x = 1;
+        seg.from = (p);
+        moveAway(((seg.from)));
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "101\n");
+}
+
+#[test]
+fn wrong_struct_uses_do_not_compile() {
+    let cases = [
+        ("write(p.z);", "Struct Point has no field z"),
+        ("write(p);", "p is a struct Point, use one of its fields like p.x"),
+        ("p = seg;", "Struct p can only be assigned another struct Point"),
+        ("p = 1.0;", "Struct p can only be assigned another struct Point"),
+        ("write(length(p));", "must be a struct Segment"),
+        ("moveAway(seg.from.x);", "must be a struct Point"),
+        ("moveAway(p + 1);", "must be a struct Point. Got: p + 1")
+    ];
+    for (body, expected) in cases.iter() {
+        let err = compile_str(&program(SHAPES, "Point: p;", body)).unwrap_err().to_string();
+        assert!(err.contains(expected), "{}: {}", body, err);
+    }
+}
+
+#[test]
+fn struct_declarations_are_checked() {
+    let undeclared = "Program Bad;
+var Circle: c;
+void module main() {
+    {
+    }
+}
+";
+    let err = compile_str(undeclared).unwrap_err().to_string();
+    assert!(err.contains("Type Circle is not declared"), "{}", err);
+
+    let recursive = "Program Bad;
+struct Node { int value; Node next; }
+void module main() {
+    {
+    }
+}
+";
+    let err = compile_str(recursive).unwrap_err().to_string();
+    assert!(err.contains("cannot have a field of its own type"), "{}", err);
+
+    let repeated = "Program Bad;
+struct Point { float x; float x; }
+void module main() {
+    {
+    }
+}
+";
+    let err = compile_str(repeated).unwrap_err().to_string();
+    assert!(err.contains("Field x is repeated"), "{}", err);
+}
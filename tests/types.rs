@@ -0,0 +1,155 @@
+mod common;
+
+use common::{program, run_program};
+use me_myself::compile_str;
+
+// A char c and an int n for a main module
+const CHARS: &str = "Program Types;
+var char: c; int: n;
+";
+
+#[test]
+fn char_literals_take_digits_punctuation_and_escapes() {
+    let source = program(CHARS, "", "
+        writeNoLn('1', ' ', '#', 'ñ');
+        c = '\\n';
+        writeNoLn(c);
+        writeNoLn('\\'', '\\t', '\\\\');
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "1   # ñ\n' \t \\");
+}
+
+#[test]
+fn ord_and_chr_convert_code_points() {
+    let source = program(CHARS, "", "
+        n = ord('A');
+        c = chr(n + 1);
+        write(n, c, ord('\\n'), chr(955));
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "65 B 10 λ\n");
+}
+
+#[test]
+fn chr_of_an_invalid_code_point_is_a_runtime_error() {
+    let source = program(CHARS, "", "
+        c = chr(-1);
+    ");
+    let err = run_program(&source, "").unwrap_err();
+    assert!(err.contains("chr(-1)"), "{}", err);
+}
+
+#[test]
+fn chars_compare_by_code_point() {
+    let source = program(CHARS, "", "
+        c = 'b';
+        if (c > 'a' & c < 'c') then {
+            write(\"between\");
+        }
+        if ('Z' < 'a' & '9' >= '0') then {
+            if (c <= 'b') then {
+                write(\"ordered\");
+            }
+        }
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "between\nordered\n");
+}
+
+#[test]
+fn switch_takes_any_char_case() {
+    let source = program(CHARS, "", "
+        c = ' ';
+        switch (c) {
+            case 'a': write(\"letter\");
+            case ' ': write(\"space\");
+            default: write(\"other\");
+        }
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "space\n");
+}
+
+#[test]
+fn char_literal_must_be_a_single_character() {
+    assert!(compile_str(&program(CHARS, "", "c = 'ab';")).is_err());
+    assert!(compile_str(&program(CHARS, "", "c = '';")).is_err());
+    assert!(compile_str(&program(CHARS, "", "c = '\\q';")).is_err());
+}
+
+// int, float and char variables and modules that take and return floats
+const TYPED: &str = "Program Types;
+var int: i; float: f; char: c;
+
+float module half(float x) {
+    {
+        return (x / 2);
+    }
+}
+
+float module one() {
+    {
+        return (1);
+    }
+}
+";
+
+// Gives i, f and c a value each before the body
+fn typed_program(body: &str) -> String {
+    program(TYPED, "", &format!("        i = 66;\n        f = 66.7;\n        c = 'B';\n{}", body))
+}
+
+#[test]
+fn casts_convert_every_type_pair() {
+    let source = typed_program("
+        write(int(i), float(i), char(i));
+        write(int(f), float(f), char(f));
+        write(int(c), float(c), char(c));
+        f = -2.9;
+        write(int(f), float(f), int(-0.5));
+    ");
+    let output = run_program(&source, "").unwrap();
+    assert_eq!(output, "66 66 B\n66 66.7 B\n66 66 B\n-2 -2.9 0\n");
+}
+
+#[test]
+fn only_int_to_float_is_implicit() {
+    // Target and value of each assignment, and what it writes when it compiles
+    let matrix = [
+        ("i", "i", Some("66")),
+        ("i", "f", None),
+        ("i", "c", None),
+        ("f", "i", Some("66")),
+        ("f", "f", Some("66.7")),
+        ("f", "c", None),
+        ("c", "i", None),
+        ("c", "f", None),
+        ("c", "c", Some("B"))
+    ];
+    for (target, value, expected) in matrix.iter() {
+        let source = typed_program(&format!("{} = {};\nwrite({});", target, value, target));
+        match expected {
+            Some(expected) => {
+                let output = run_program(&source, "").unwrap();
+                assert_eq!(output, format!("{}\n", expected), "{} = {}", target, value);
+            }
+            None => assert!(compile_str(&source).is_err(), "{} = {} should not compile", target, value)
+        }
+    }
+}
+
+#[test]
+fn params_and_returns_promote_like_assignments() {
+    let output = run_program(&typed_program("write(half(5), half(i), one());"), "").unwrap();
+    assert_eq!(output, "2.5 33 1\n");
+    assert!(compile_str(&typed_program("write(half(c));")).is_err());
+}
+
+#[test]
+fn casts_out_of_range_are_runtime_errors() {
+    let err = run_program(&typed_program("i = int(30000000000.0);"), "").unwrap_err();
+    assert!(err.contains("does not fit in a 32 bit int"), "{}", err);
+    let err = run_program(&typed_program("c = char(-1.5);"), "").unwrap_err();
+    assert!(err.contains("not a valid character"), "{}", err);
+}